@@ -0,0 +1,187 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+
+use crate::download_piece;
+use crate::handshake::PeerConnection;
+use crate::message::PeerMessage;
+use crate::random;
+
+/// How often the unchoked peers are picked again
+pub const RECHOKE_INTERVAL: Duration = Duration::from_secs(10);
+/// Rechokes between moving the optimistic unchoke to another peer, 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+/// Peers unchoked for giving us the most, on top of the optimistic one
+const UPLOAD_SLOTS: usize = 4;
+/// A peer that lets us download but sends nothing for this long is snubbing us
+const SNUB_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Decides which peers get to download from us, tit-for-tat: the peers we download the most
+/// from are unchoked, or the ones taking the most while we seed, plus one picked at random
+/// so newcomers get a chance to show what they give back
+#[derive(Default)]
+pub struct Choker {
+    state: Mutex<ChokerState>,
+    /// Woken whenever the unchoked peers change
+    rechoked: Notify,
+}
+
+#[derive(Default)]
+struct ChokerState {
+    peers: Vec<ChokedPeer>,
+    /// Rechokes since the optimistic unchoke last moved
+    rounds: u32,
+}
+
+struct ChokedPeer {
+    connection: Arc<PeerConnection>,
+    /// Transfer counters at the last rechoke, to tell the rate since then
+    downloaded: u64,
+    uploaded: u64,
+    /// Bytes transferred during the last interval, in the direction that matters right now
+    rate: u64,
+    unchoked: bool,
+    optimistic: bool,
+}
+
+impl Choker {
+    /// Starts deciding for a freshly connected peer, choked until the next rechoke
+    pub fn register(&self, connection: Arc<PeerConnection>) {
+        let mut state = self.state.lock().unwrap();
+        let (downloaded, uploaded) = {
+            let peer_state = connection.state.lock().unwrap();
+            (peer_state.downloaded, peer_state.uploaded)
+        };
+        state.peers.push(ChokedPeer {
+            connection,
+            downloaded,
+            uploaded,
+            rate: 0,
+            unchoked: false,
+            optimistic: false,
+        });
+    }
+
+    /// Forgets a peer that left, handing its slot to someone else
+    pub fn unregister(&self, connection: &PeerConnection) {
+        let mut state = self.state.lock().unwrap();
+        let before = state.peers.len();
+        state.peers.retain(|peer| !is_same(peer, connection));
+        if state.peers.len() != before {
+            self.rechoke_peers(&mut state);
+        }
+    }
+
+    /// Measures every peer over the last interval, marks the ones snubbing us and picks who
+    /// to unchoke; when `seeding` peers are ranked by what we sent them instead
+    pub fn tick(&self, seeding: bool) {
+        let mut state = self.state.lock().unwrap();
+        for peer in &mut state.peers {
+            let mut peer_state = peer.connection.state.lock().unwrap();
+            peer.rate = match seeding {
+                true => peer_state.uploaded - peer.uploaded,
+                false => peer_state.downloaded - peer.downloaded,
+            };
+            peer.downloaded = peer_state.downloaded;
+            peer.uploaded = peer_state.uploaded;
+
+            peer_state.snubbed = peer_state.am_interested
+                && !peer_state.peer_choking
+                && peer_state.last_block.elapsed() > SNUB_TIMEOUT;
+        }
+
+        state.rounds += 1;
+        if state.rounds >= OPTIMISTIC_ROUNDS {
+            state.rounds = 0;
+            for peer in &mut state.peers {
+                peer.optimistic = false;
+            }
+        }
+        self.rechoke_peers(&mut state);
+    }
+
+    /// Picks again with the rates already measured, when a peer's interest changed
+    pub fn rechoke(&self) {
+        let mut state = self.state.lock().unwrap();
+        self.rechoke_peers(&mut state);
+    }
+
+    /// Resolves the next time the unchoked peers change
+    pub async fn rechoked(&self) {
+        self.rechoked.notified().await
+    }
+
+    /// Tells the peer whether it may download from us, if that changed since the last time
+    pub async fn apply(&self, connection: &PeerConnection) -> Result<(), io::Error> {
+        let unchoked = {
+            let state = self.state.lock().unwrap();
+            state
+                .peers
+                .iter()
+                .any(|peer| is_same(peer, connection) && peer.unchoked)
+        };
+        let changed = {
+            let mut peer_state = connection.state.lock().unwrap();
+            let changed = peer_state.am_choking == unchoked;
+            peer_state.am_choking = !unchoked;
+            //? Choking a peer discards whatever it asked for
+            if !unchoked {
+                peer_state.requested.clear();
+            }
+            changed
+        };
+
+        if changed {
+            let message = match unchoked {
+                true => PeerMessage::Unchoke,
+                false => PeerMessage::Choke,
+            };
+            download_piece::send_message(connection, &message).await?;
+        }
+        Ok(())
+    }
+
+    fn rechoke_peers(&self, state: &mut ChokerState) {
+        //? Only interested peers need a slot, the ones snubbing us can only get the optimistic one
+        let mut ranked: Vec<usize> = (0..state.peers.len())
+            .filter(|&index| {
+                let peer_state = state.peers[index].connection.state.lock().unwrap();
+                peer_state.peer_interested && !peer_state.snubbed
+            })
+            .collect();
+        ranked.sort_by_key(|&index| std::cmp::Reverse(state.peers[index].rate));
+        ranked.truncate(UPLOAD_SLOTS);
+
+        for (index, peer) in state.peers.iter_mut().enumerate() {
+            peer.unchoked = ranked.contains(&index);
+        }
+
+        //? Keep the optimistic unchoke where it is until it is due to move, unless the peer
+        //? earned a regular slot or lost interest
+        let interested = |peer: &ChokedPeer| peer.connection.state.lock().unwrap().peer_interested;
+        for peer in &mut state.peers {
+            if peer.optimistic && (peer.unchoked || !interested(peer)) {
+                peer.optimistic = false;
+            }
+        }
+        if !state.peers.iter().any(|peer| peer.optimistic) {
+            let candidates: Vec<usize> = (0..state.peers.len())
+                .filter(|&index| !state.peers[index].unchoked && interested(&state.peers[index]))
+                .collect();
+            if !candidates.is_empty() {
+                let index = candidates[random::below(candidates.len())];
+                state.peers[index].optimistic = true;
+            }
+        }
+        for peer in &mut state.peers {
+            peer.unchoked |= peer.optimistic;
+        }
+
+        self.rechoked.notify_waiters();
+    }
+}
+
+fn is_same(peer: &ChokedPeer, connection: &PeerConnection) -> bool {
+    std::ptr::eq(Arc::as_ptr(&peer.connection), connection)
+}
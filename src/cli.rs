@@ -2,6 +2,9 @@ use std::path::PathBuf;
 
 use clap::{Parser, Subcommand};
 
+use crate::handshake;
+use crate::info::TorrentSource;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
@@ -18,26 +21,26 @@ pub enum Commands {
     },
     /// Gets info about a torrent file
     Info {
-        /// A torrent file to decode
-        torrent_file: PathBuf,
+        /// A torrent file or magnet link to decode
+        torrent_file: TorrentSource,
     },
     /// Gets peers from a torrent file
     Peers {
-        /// A torrent file to decode
-        torrent_file: PathBuf,
+        /// A torrent file or magnet link to decode
+        torrent_file: TorrentSource,
     },
     /// Gets the peer ID from a handshake
     Handshake {
-        /// A torrent file to decode
-        torrent_file: PathBuf,
+        /// A torrent file or magnet link to decode
+        torrent_file: TorrentSource,
         /// Peer IP address and port
         peer: String,
     },
     /// Downloads a piece from a torrent file
     #[command(name = "download_piece")]
     DownloadPiece {
-        /// A torrent file to decode
-        torrent_file: PathBuf,
+        /// A torrent file or magnet link to decode
+        torrent_file: TorrentSource,
         /// Piece index
         piece_index: usize,
         /// Output path
@@ -45,10 +48,53 @@ pub enum Commands {
         output_path: PathBuf,
     },
     Download {
-        /// A torrent file to decode
-        torrent_file: PathBuf,
+        /// A torrent file or magnet link to decode
+        torrent_file: TorrentSource,
         /// Output path
         #[arg(short, long, value_name = "OUTPUT_PATH")]
         output_path: PathBuf,
+        /// Keep uploading to peers after the download completes
+        #[arg(long)]
+        seed: bool,
+        /// Download limit for everything this process does, in KiB/s
+        #[arg(long, value_name = "KIB_PER_SEC")]
+        download_limit: Option<u64>,
+        /// Upload limit for everything this process does, in KiB/s
+        #[arg(long, value_name = "KIB_PER_SEC")]
+        upload_limit: Option<u64>,
+        /// Download limit for this torrent, in KiB/s
+        #[arg(long, value_name = "KIB_PER_SEC")]
+        torrent_download_limit: Option<u64>,
+        /// Upload limit for this torrent, in KiB/s
+        #[arg(long, value_name = "KIB_PER_SEC")]
+        torrent_upload_limit: Option<u64>,
+        /// How long a peer gets to accept our connection, in seconds
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = handshake::CONNECT_TIMEOUT.as_secs()
+        )]
+        connect_timeout: u64,
+        /// How long a peer gets to exchange handshakes with us, in seconds
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = handshake::HANDSHAKE_TIMEOUT.as_secs()
+        )]
+        handshake_timeout: u64,
+        /// How long a peer gets to answer a request, in seconds
+        #[arg(
+            long,
+            value_name = "SECONDS",
+            default_value_t = handshake::REQUEST_TIMEOUT.as_secs()
+        )]
+        request_timeout: u64,
+    },
+    /// Checks downloaded data against a torrent file, without touching the network
+    Verify {
+        /// A torrent file to check against
+        torrent_file: PathBuf,
+        /// The downloaded file, or directory for a multi-file torrent
+        path: PathBuf,
     },
 }
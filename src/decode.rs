@@ -1,6 +1,7 @@
 use serde::{Deserialize, Serialize};
 use serde_bencode::value::Value as ExternalBencodeValue;
 use serde_json::{Map, Value};
+use std::ops::Range;
 
 #[derive(Serialize, Deserialize)]
 pub struct BencodeValue(pub ExternalBencodeValue);
@@ -33,6 +34,70 @@ impl BencodeValue {
     }
 }
 
+/// Byte range of the value stored under `key` in a bencoded dictionary,
+/// so the exact original encoding can be recovered (e.g. for the info-hash)
+pub fn dict_value_span(bytes: &[u8], key: &[u8]) -> Option<Range<usize>> {
+    if bytes.first() != Some(&b'd') {
+        return None;
+    }
+
+    let mut position = 1;
+    while *bytes.get(position)? != b'e' {
+        let key_end = value_end(bytes, position)?;
+        let key_start = position + bytes[position..].iter().position(|&byte| byte == b':')? + 1;
+        let end = value_end(bytes, key_end)?;
+
+        if &bytes[key_start..key_end] == key {
+            return Some(key_end..end);
+        }
+        position = end;
+    }
+
+    None
+}
+
+/// Lists and dictionaries nested deeper than this are refused, real torrents stay far below
+const MAX_NESTING: usize = 64;
+
+/// Index right past the bencoded value starting at `start`
+pub fn value_end(bytes: &[u8], start: usize) -> Option<usize> {
+    //? Walks the value without recursing, so a peer nesting lists a million deep can not
+    //? overflow the stack, and caps the nesting for whoever parses the value afterwards
+    let mut position = start;
+    let mut depth = 0;
+    loop {
+        match bytes.get(position)? {
+            b'e' if depth > 0 => {
+                depth -= 1;
+                position += 1;
+            }
+            b'l' | b'd' => {
+                depth += 1;
+                if depth > MAX_NESTING {
+                    return None;
+                }
+                position += 1;
+            }
+            b'i' => position += bytes[position..].iter().position(|&byte| byte == b'e')? + 1,
+            b'0'..=b'9' => {
+                let colon = position + bytes[position..].iter().position(|&byte| byte == b':')?;
+                let length: usize = std::str::from_utf8(&bytes[position..colon])
+                    .ok()?
+                    .parse()
+                    .ok()?;
+                position = colon.checked_add(1)?.checked_add(length)?;
+                if position > bytes.len() {
+                    return None;
+                }
+            }
+            _ => return None,
+        }
+        if depth == 0 {
+            return Some(position);
+        }
+    }
+}
+
 //? old code
 #[allow(dead_code)]
 pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
@@ -66,7 +131,7 @@ pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
             }
         }
 
-        (serde_json::Value::Object(dict), &remaining)
+        (serde_json::Value::Object(dict), remaining)
     } else if next == 'l' {
         let mut remaining = &encoded_value[1..encoded_value.len()];
         let mut list = Vec::new();
@@ -80,7 +145,7 @@ pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
             list.push(decoded_value);
         }
 
-        (serde_json::Value::Array(list), &remaining)
+        (serde_json::Value::Array(list), remaining)
     } else if next == 'i' {
         let e_index = encoded_value.find('e').unwrap();
         let number_string = &encoded_value[1..e_index];
@@ -104,3 +169,40 @@ pub fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
         panic!("Unhandled encoded value: {}", encoded_value)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn value_end_skips_nested_values() {
+        let bytes = b"d3:keyli1e4:spamde1:xi-3eee5:after";
+        assert_eq!(value_end(bytes, 0), Some(27));
+        assert_eq!(value_end(bytes, 1), Some(6));
+        assert_eq!(value_end(bytes, 6), Some(26));
+    }
+
+    #[test]
+    fn value_end_refuses_truncated_values() {
+        assert_eq!(value_end(b"li1e", 0), None);
+        assert_eq!(value_end(b"5:spa", 0), None);
+        assert_eq!(value_end(b"e", 0), None);
+    }
+
+    #[test]
+    fn value_end_refuses_deep_nesting() {
+        let nested = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert_eq!(value_end(&nested(MAX_NESTING), 0), Some(2 * MAX_NESTING));
+        assert_eq!(value_end(&nested(MAX_NESTING + 1), 0), None);
+        //? Would overflow the stack if every level took a call
+        assert_eq!(value_end(&vec![b'l'; 1_000_000], 0), None);
+    }
+
+    #[test]
+    fn dict_value_span_finds_the_raw_value() {
+        let bytes = b"d8:announce3:url4:infod6:lengthi5eee";
+        let span = dict_value_span(bytes, b"info").unwrap();
+        assert_eq!(&bytes[span], b"d6:lengthi5ee");
+        assert_eq!(dict_value_span(bytes, b"missing"), None);
+    }
+}
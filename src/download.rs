@@ -1,165 +1,483 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
-use std::sync::Arc;
-use tokio::fs::OpenOptions;
-use tokio::io::{self, AsyncSeekExt, AsyncWriteExt};
-use tokio::net::TcpStream;
-use tokio::sync::RwLock;
-use tokio::task::JoinHandle;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io;
+use tokio::sync::{mpsc, RwLock};
+use tokio::task::JoinSet;
+use tokio::time;
 
+use crate::choker::{self, Choker};
+use crate::download_piece::Block;
+use crate::handshake::{PeerConnection, Timeouts};
+use crate::listener::{self, InboundPeer, Listener};
+use crate::message::PeerMessage;
+use crate::picker::WorkQueue;
+use crate::pipeline::Pipeline;
+use crate::rate_limit::{RateLimit, RateLimits};
+use crate::resume::ResumeFile;
+use crate::storage::Storage;
+use crate::upload::Uploader;
+use crate::verify::{self, PieceStatus};
 use crate::{download_piece, handshake, info, peers};
 
-#[allow(unused_variables)]
-pub async fn download(path: &Path, output_path: &Path) -> Result<(), std::io::Error> {
-    let metadata = RwLock::new(info::get_info(path));
-    let peers = peers::get_peers(&metadata).await;
-    let piece_hashes = metadata.read().await.info.get_piece_hashes();
+/// Pieces failing the hash check a peer may send before we stop talking to it
+const MAX_STRIKES: u32 = 3;
+/// Requests in a row a peer may leave unanswered before we stop talking to it
+const MAX_TIMEOUTS: u32 = 3;
+/// Request timeouts a peer may go without sending anything, keep-alives included, before we
+/// stop talking to it; peers send a keep-alive at least every 2 minutes
+const IDLE_TIMEOUTS: u32 = 5;
+/// How often we send a keep-alive of our own, so peers do not drop us while nothing happens
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
 
-    let metadata = Arc::new(metadata);
-    let peer_tasks_handles = peers.iter().map(|peer: &String| {
-        // let metadata = metadata.clone();
-        let peer = peer.clone();
-        let metadata = metadata.clone();
-
-        tokio::spawn(async move {
-            let (_, stream) = handshake::get_handshake(&metadata, &peer).await;
-            let bitmap = download_piece::get_bitfield(&stream).await;
-
-            PeerTask {
-                stream,
-                bitmap,
-                pieces: Vec::new(),
-                metadata,
-            }
-        })
-    });
-
-    let mut peer_tasks = Vec::with_capacity(peer_tasks_handles.len());
-    for task in peer_tasks_handles {
-        peer_tasks.push(task.await.expect("Failed to get peer stream"));
-    }
-
-    let mut not_found_counter = 0;
-    for piece in piece_hashes.iter().enumerate() {
-        let (piece_idx, piece_hash) = piece;
-        let peer_idx = piece_idx % peer_tasks.len();
-
-        if peer_tasks[peer_idx].bitmap[piece_idx] {
-            peer_tasks[peer_idx].pieces.push(Piece {
-                index: piece_idx as u32,
-                hash: piece_hash.clone(),
-            });
-        } else {
-            not_found_counter += 1;
-            if not_found_counter >= peer_tasks.len() {
-                return Err(io::Error::new(
-                    io::ErrorKind::Other,
-                    "No peers have this piece",
-                ));
+/// Downloads the torrent to `output_path`, uploading to peers along the way;
+/// with `seed` it keeps uploading after the download completes, until interrupted.
+/// Transfers with every peer together stay within `limits`, peers that do not answer
+/// within `timeouts` are dropped
+pub async fn download(
+    metadata: info::Metadata,
+    output_path: &Path,
+    seed: bool,
+    limits: RateLimits,
+    timeouts: Timeouts,
+) -> Result<(), std::io::Error> {
+    let piece_hashes = Arc::new(metadata.info.get_piece_hashes());
+    let storage = Arc::new(Storage::open(&metadata.info, output_path).await?);
+    let queue = Arc::new(WorkQueue::new(piece_hashes.len()));
+    let resume = ResumeFile::new(output_path, metadata.info.get_hash());
+
+    //? Whatever an earlier run left behind only has to be fetched if it does not check out,
+    //? unless the resume file vouches for it
+    let existing = match resume.load(&storage, piece_hashes.len()).await {
+        Some(existing) => existing,
+        None => {
+            let existing: Vec<bool> = verify::check_pieces(&metadata.info, &storage)
+                .await?
+                .into_iter()
+                .map(|status| status == PieceStatus::Valid)
+                .collect();
+            if let Err(e) = resume.save(&storage, &existing).await {
+                eprintln!("Failed to save resume file: {}", e);
             }
+            existing
         }
+    };
+    let existing_count = existing.iter().filter(|&&valid| valid).count();
+    if existing_count > 0 {
+        eprintln!(
+            "Resuming with {}/{} pieces already downloaded",
+            existing_count,
+            piece_hashes.len()
+        );
+    }
+    for (piece_index, _) in existing.iter().enumerate().filter(|(_, &valid)| valid) {
+        queue.complete(piece_index);
     }
+    if queue.is_done() && !seed {
+        return Ok(());
+    }
+
+    let info_hash = metadata.info.get_hash();
+    let metadata = RwLock::new(metadata);
+    let peers = peers::get_peers(&metadata)
+        .await
+        .map_err(io::Error::other)?;
+    let strikes = Arc::new(Strikes::default());
 
-    let file = Arc::new(RwLock::new(
-        OpenOptions::new()
-            .read(true)
-            .write(true)
-            .create(true)
-            .open(output_path)
-            .await?,
+    let metadata = Arc::new(metadata);
+    let uploader = Arc::new(Uploader::new(
+        metadata.clone(),
+        storage.clone(),
+        queue.clone(),
+        limits.upload,
     ));
+    let choker = Arc::new(Choker::default());
+    let peer_task = PeerTask {
+        metadata: metadata.clone(),
+        piece_hashes: piece_hashes.clone(),
+        queue: queue.clone(),
+        storage: storage.clone(),
+        strikes: strikes.clone(),
+        uploader,
+        choker: choker.clone(),
+        download_limit: limits.download,
+        timeouts,
+        seed,
+    };
+    let mut peer_tasks = JoinSet::new();
+    for peer in peers {
+        let peer_task = peer_task.clone();
+        peer_tasks.spawn(async move {
+            if let Err(e) = peer_task.run(&peer).await {
+                eprintln!("Dropping peer {}: {}", peer, e);
+            }
+        });
+    }
 
-    let peer_tasks: Vec<Arc<PeerTask>> = peer_tasks.into_iter().fold(Vec::new(), |mut acc, p_t| {
-        acc.push(Arc::new(p_t));
-        acc
-    });
+    //? Without the listener we only miss out on peers that find us, not worth giving up for
+    let listener = match Listener::listen(listener::PORT, timeouts).await {
+        Ok(listener) => Some(listener),
+        Err(e) => {
+            eprintln!("Not accepting peers on port {}: {}", listener::PORT, e);
+            None
+        }
+    };
+    let mut inbound = listener
+        .as_ref()
+        .map(|listener| listener.register(info_hash));
 
-    let peer_tasks_handles =
-        peer_tasks
-            .iter()
-            .map(|peer_task| -> JoinHandle<Result<(), io::Error>> {
-                let file = file.clone();
+    //? Done as soon as every piece is verified, even if some peers are still hanging around,
+    //? unless seeding. Progress goes to the resume file from here, peer tasks may be aborted
+    //? at any point
+    let mut interrupted = false;
+    let mut rechoke = time::interval(choker::RECHOKE_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = rechoke.tick() => choker.tick(queue.is_done()),
+            _ = queue.finished(), if !seed => break,
+            _ = queue.progressed() => save_resume(&resume, &storage, &queue).await,
+            Some((peer, connection)) = next_inbound(&mut inbound) => {
                 let peer_task = peer_task.clone();
-                let piece_hashes = piece_hashes.clone();
-
-                tokio::spawn(async move {
-                    //? Send interested message
-                    peer_task
-                        .stream
-                        .write()
-                        .await
-                        .write_all(&[0, 0, 0, 1, 2])
-                        .await?;
-
-                    //? Unchoke message
-                    let message = download_piece::receive_message(&peer_task.stream).await?;
-                    assert!(message.id == 1);
-
-                    for piece in peer_task.pieces.iter() {
-                        let piece_index = piece.index;
-                        let pieces_count = piece_hashes.len() as u32;
-                        let piece_hash = &piece.hash.clone();
-
-                        let metadata = peer_task.metadata.read().await;
-                        let piece_length = if piece_index == pieces_count - 1 {
-                            metadata.info.length - (piece_index * metadata.info.piece_length)
-                        } else {
-                            metadata.info.piece_length
-                        };
-                        let piece_position = piece_index * metadata.info.piece_length;
-
-                        //? Piece blocks messages to send
-                        let piece_blocks_messages =
-                            download_piece::get_piece_blocks_messages(piece_index, piece_length);
-
-                        //? Received piece blocks
-                        let piece_blocks = download_piece::receive_piece_blocks(
-                            &peer_task.stream,
-                            piece_blocks_messages,
-                        )
-                        .await;
-
-                        let piece = download_piece::combine_blocks_into_piece(
-                            piece_blocks,
-                            piece_length,
-                            piece_index,
-                            piece_hash,
-                        );
-
-                        write_at_position(file.clone(), piece_position as u64, &piece).await?;
+                peer_tasks.spawn(async move {
+                    if let Err(e) = peer_task.serve(connection).await {
+                        eprintln!("Dropping peer {}: {}", peer, e);
                     }
+                });
+            }
+            Some(task) = peer_tasks.join_next() => task.expect("Peer task panicked"),
+            _ = tokio::signal::ctrl_c() => {
+                interrupted = true;
+                break;
+            }
+        }
+        //? Out of peers, a seed still waits for someone to connect
+        if peer_tasks.is_empty() && !(seed && inbound.is_some()) {
+            break;
+        }
+    }
+    if let Some(listener) = &listener {
+        listener.unregister(&info_hash);
+    }
+    peer_tasks.abort_all();
+    save_resume(&resume, &storage, &queue).await;
 
-                    Ok(())
-                })
-            });
-
-    let mut peer_tasks = Vec::with_capacity(peer_tasks_handles.len());
-    for task in peer_tasks_handles {
-        peer_tasks.push(task.await.expect("Failed to get peer stream"));
+    if interrupted && !queue.is_done() {
+        return Err(io::Error::new(
+            io::ErrorKind::Interrupted,
+            format!("Interrupted with {} pieces left", queue.remaining()),
+        ));
+    }
+    if !queue.is_done() {
+        return Err(io::Error::other(format!(
+            "Ran out of peers with {} pieces left",
+            queue.remaining()
+        )));
     }
 
     Ok(())
 }
 
+/// The next peer that connected to us, never resolves without a listener
+async fn next_inbound(inbound: &mut Option<mpsc::Receiver<InboundPeer>>) -> Option<InboundPeer> {
+    match inbound {
+        Some(inbound) => inbound.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn save_resume(resume: &ResumeFile, storage: &Storage, queue: &WorkQueue) {
+    if let Err(e) = resume.save(storage, &queue.verified()).await {
+        eprintln!("Failed to save resume file: {}", e);
+    }
+}
+
+#[derive(Clone)]
 struct PeerTask {
-    stream: RwLock<TcpStream>,
-    bitmap: Vec<bool>,
-    pieces: Vec<Piece>,
     metadata: Arc<RwLock<info::Metadata>>,
+    piece_hashes: Arc<Vec<String>>,
+    queue: Arc<WorkQueue>,
+    storage: Arc<Storage>,
+    strikes: Arc<Strikes>,
+    uploader: Arc<Uploader>,
+    choker: Arc<Choker>,
+    download_limit: RateLimit,
+    timeouts: Timeouts,
+    seed: bool,
 }
 
-struct Piece {
-    index: u32,
-    hash: String,
+/// Pieces that failed the hash check, per peer IP so a banned peer cannot come back on
+/// another port
+#[derive(Default)]
+struct Strikes(Mutex<HashMap<IpAddr, u32>>);
+
+impl Strikes {
+    /// Records a bad piece and returns how many the peer has sent so far
+    fn add(&self, ip: IpAddr) -> u32 {
+        let mut strikes = self.0.lock().unwrap();
+        let count = strikes.entry(ip).or_default();
+        *count += 1;
+        *count
+    }
+
+    fn is_banned(&self, ip: IpAddr) -> bool {
+        self.0.lock().unwrap().get(&ip).copied().unwrap_or(0) >= MAX_STRIKES
+    }
 }
 
-#[allow(dead_code)]
-async fn write_at_position(
-    file: Arc<RwLock<tokio::fs::File>>,
-    position: u64,
-    data: &[u8],
-) -> io::Result<()> {
-    let mut file = file.write().await;
-    file.seek(io::SeekFrom::Start(position)).await?;
-    file.write_all(data).await?;
-    Ok(())
+impl PeerTask {
+    async fn run(&self, peer: &str) -> Result<(), io::Error> {
+        //? Peers given by host name are only checked once connected
+        let address = peer.parse::<SocketAddr>();
+        if address.is_ok_and(|address| self.strikes.is_banned(address.ip())) {
+            return Err(io::Error::other("Peer is banned"));
+        }
+
+        let connection = handshake::get_handshake(&self.metadata, peer, &self.timeouts)
+            .await
+            .map_err(io::Error::other)?;
+        self.serve(connection).await
+    }
+
+    /// Takes over a connection once the handshake is done, whoever started it
+    async fn serve(&self, connection: PeerConnection) -> Result<(), io::Error> {
+        if self.strikes.is_banned(connection.address.ip()) {
+            return Err(io::Error::other("Peer is banned"));
+        }
+
+        connection.set_piece_count(self.piece_hashes.len());
+        let connection = Arc::new(connection);
+        let _ = connection.uploader.set(self.uploader.clone());
+        self.uploader.greet(&connection).await?;
+        self.choker.register(connection.clone());
+
+        //? Keep what this peer adds to the availability, so it can be taken back when it leaves
+        let mut counted = Vec::new();
+        let mut pipeline = Pipeline::default();
+        let result = tokio::select! {
+            result = self.exchange_pieces(&connection, &mut counted, &mut pipeline) => result,
+            Err(e) = self.uploader.upload(&connection) => Err(e),
+        };
+        //? Pieces still in progress go back to the other peers
+        for piece_index in pipeline.clear() {
+            self.queue.release(piece_index as usize);
+        }
+        self.queue.update_availability(&counted, &[]);
+        self.choker.unregister(&connection);
+
+        //? Name the client in the log, it helps telling a misbehaving one apart
+        result.map_err(|e| match connection.client() {
+            Some(client) => io::Error::new(e.kind(), format!("{} ({})", e, client)),
+            None => e,
+        })
+    }
+
+    /// Pulls pieces off the queue while serving the peer's requests, taking on the next piece
+    /// before the current ones are in so the peer never sits idle. Pieces go back to the queue
+    /// when the peer chokes us, and are dropped once another peer finishes them first.
+    /// Ends once every piece is verified, unless we stay to seed
+    async fn exchange_pieces(
+        &self,
+        connection: &PeerConnection,
+        counted: &mut Vec<bool>,
+        pipeline: &mut Pipeline,
+    ) -> Result<(), io::Error> {
+        //? Pieces this peer sent bad data for or left hanging, better fetched from someone else
+        let mut failed = vec![false; self.piece_hashes.len()];
+        let mut peer_interested = false;
+        let mut timeouts = 0;
+        let mut last_message = Instant::now();
+        let idle_timeout = self.timeouts.request * IDLE_TIMEOUTS;
+        let mut keep_alive = time::interval_at(
+            (Instant::now() + KEEP_ALIVE_INTERVAL).into(),
+            KEEP_ALIVE_INTERVAL,
+        );
+        loop {
+            self.uploader.announce(connection).await?;
+
+            //? A peer turning interested should not wait for the next round to be unchoked
+            if connection.state.lock().unwrap().peer_interested != peer_interested {
+                peer_interested = !peer_interested;
+                self.choker.rechoke();
+            }
+            self.choker.apply(connection).await?;
+
+            //? Haves that came in since the last piece count towards the availability
+            let bitfield = connection.bitfield();
+            self.queue.update_availability(counted, &bitfield);
+            *counted = bitfield;
+
+            let done = self.queue.is_done();
+            if done && !self.seed {
+                return Ok(());
+            }
+            let interested = !done;
+            if std::mem::replace(
+                &mut connection.state.lock().unwrap().am_interested,
+                interested,
+            ) != interested
+            {
+                let message = match interested {
+                    true => PeerMessage::Interested,
+                    false => PeerMessage::NotInterested,
+                };
+                download_piece::send_message(connection, &message).await?;
+            }
+
+            //? In the endgame another peer may finish a piece we are still fetching
+            for piece_index in pipeline.pieces() {
+                if self.queue.is_verified(piece_index as usize) {
+                    for cancel in pipeline.abandon(piece_index) {
+                        download_piece::send_message(connection, &cancel).await?;
+                    }
+                    self.queue.release(piece_index as usize);
+                }
+            }
+
+            //? Hand pieces the peer stopped answering for to the other peers
+            let timed_out = pipeline.timed_out(self.timeouts.request);
+            if !timed_out.is_empty() {
+                timeouts += 1;
+                if timeouts >= MAX_TIMEOUTS {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Dropped after leaving requests unanswered",
+                    ));
+                }
+                for piece_index in timed_out {
+                    for cancel in pipeline.abandon(piece_index) {
+                        download_piece::send_message(connection, &cancel).await?;
+                    }
+                    failed[piece_index as usize] = true;
+                    self.queue.release(piece_index as usize);
+                }
+            }
+
+            //? A choke discards everything we asked for
+            let choking = connection.is_choking();
+            if choking {
+                for piece_index in pipeline.clear() {
+                    self.queue.release(piece_index as usize);
+                }
+            }
+            let depth = pipeline.depth(connection.reqq());
+            for request in pipeline.requests(depth) {
+                download_piece::send_message(connection, &request).await?;
+            }
+            let deadline = pipeline.deadline(self.timeouts.request);
+
+            //? Only take on more work while the peer lets us download, and keep serving meanwhile
+            tokio::select! {
+                message = download_piece::next_message(connection) => {
+                    last_message = Instant::now();
+                    let Some(message) = self.uploader.serve(connection, message?) else {
+                        continue;
+                    };
+                    if let Some(PeerMessage::Piece {
+                        piece_index,
+                        begin,
+                        block,
+                    }) = download_piece::handle_message(connection, message)
+                    {
+                        download_piece::record_block(connection, &self.download_limit, block.len())
+                            .await;
+                        if pipeline.receive(piece_index, begin, block) {
+                            timeouts = 0;
+                            if let Some((piece_index, blocks)) = pipeline.finished() {
+                                self.finish_piece(connection, piece_index, blocks, &mut failed)
+                                    .await?;
+                            }
+                        }
+                    }
+                }
+                _ = self.queue.progressed() => {}
+                _ = self.choker.rechoked() => {}
+                _ = time::sleep_until(deadline.unwrap_or_else(Instant::now).into()),
+                    if deadline.is_some() => {}
+                //? Nothing else notices a peer that went quiet with no requests of ours pending
+                _ = time::sleep_until((last_message + idle_timeout).into()) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Dropped after going silent",
+                    ));
+                }
+                _ = keep_alive.tick() => {
+                    download_piece::send_message(connection, &PeerMessage::KeepAlive).await?;
+                }
+                piece_index = self.queue.next(counted, &failed, pipeline.pieces().is_empty()),
+                    if !done && !choking && pipeline.wants_piece(depth) =>
+                {
+                    if let Some(piece_index) = piece_index {
+                        let added = match self.piece_geometry(piece_index as u32).await {
+                            Ok((piece_length, _)) => {
+                                pipeline.add_piece(piece_index as u32, piece_length)
+                            }
+                            Err(e) => Err(e),
+                        };
+                        if added.is_err() {
+                            self.queue.release(piece_index);
+                        }
+                        added?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks a piece that has all of its blocks and writes it out, striking the peer if it
+    /// does not match its hash
+    async fn finish_piece(
+        &self,
+        connection: &PeerConnection,
+        piece_index: u32,
+        blocks: Vec<Option<Block>>,
+        failed: &mut [bool],
+    ) -> Result<(), io::Error> {
+        let index = piece_index as usize;
+        let written = async {
+            let (piece_length, piece_position) = self.piece_geometry(piece_index).await?;
+            let piece = download_piece::combine_blocks_into_piece(
+                blocks,
+                piece_length,
+                piece_index,
+                &self.piece_hashes[index],
+            )
+            .await?;
+            self.storage.write_at(piece_position, &piece).await
+        };
+
+        match written.await {
+            Ok(()) => self.queue.complete(index),
+            Err(e) if download_piece::is_hash_mismatch(&e) => {
+                failed[index] = true;
+                self.queue.release(index);
+
+                let ip = connection.address.ip();
+                let strikes = self.strikes.add(ip);
+                eprintln!("Peer {} strike {}/{}: {}", ip, strikes, MAX_STRIKES, e);
+                if strikes >= MAX_STRIKES {
+                    return Err(io::Error::other("Banned after sending corrupt pieces"));
+                }
+            }
+            Err(e) => {
+                self.queue.release(index);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Length of the piece and where it starts in the torrent's data
+    async fn piece_geometry(&self, piece_index: u32) -> Result<(u64, u64), io::Error> {
+        let metadata = self.metadata.read().await;
+        let piece_length = metadata.info.piece_size(piece_index).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidData, "Piece index out of range")
+        })?;
+        let piece_position = metadata
+            .info
+            .piece_offset(piece_index)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Piece offset overflows"))?;
+        Ok((piece_length, piece_position))
+    }
 }
@@ -1,98 +1,170 @@
 use sha1::{Digest, Sha1};
-use std::fmt::Display;
+use std::io;
 use std::path::Path;
+use std::time::{Duration, Instant};
 use std::vec;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use thiserror::Error;
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{oneshot, RwLock};
+use tokio::time::timeout;
 
+use crate::handshake::{PeerConnection, Timeouts};
+use crate::message::{self, PeerMessage};
+use crate::pipeline::Pipeline;
+use crate::rate_limit::RateLimit;
 use crate::{handshake, info, peers};
 
 pub const BLOCK_SIZE: u32 = 16 * 1_024;
 
+/// Fetches one piece from the first peer that hands it over, giving up on peers that do not
+/// answer within `timeouts`
 pub async fn download_piece(
-    path: &Path,
+    metadata: info::Metadata,
     piece_index: usize,
     output_path: &Path,
+    timeouts: Timeouts,
 ) -> Result<(), std::io::Error> {
-    let metadata = RwLock::new(info::get_info(path));
-    let peers = peers::get_peers(&metadata).await;
+    let metadata = RwLock::new(metadata);
+    let peers = peers::get_peers(&metadata)
+        .await
+        .map_err(io::Error::other)?;
     let piece_hashes = metadata.write().await.info.get_piece_hashes();
 
     assert!(piece_index < piece_hashes.len(), "Piece index out of range");
     let piece_hash = &piece_hashes[piece_index];
 
-    //? Handshake
-    let (_, stream) = handshake::get_handshake(&metadata, &peers[0]).await;
-
-    let bitmap = get_bitfield(&stream).await;
-    assert!(bitmap[piece_index], "Peer does not have piece");
+    //? Take the piece from the first peer that hands over one matching its hash
+    let mut piece = None;
+    for peer in peers.iter() {
+        let fetched = fetch_piece(
+            &metadata,
+            peer,
+            piece_index,
+            piece_hash,
+            piece_hashes.len(),
+            &timeouts,
+        );
+        match fetched.await {
+            Ok(peer_piece) => {
+                piece = Some(peer_piece);
+                break;
+            }
+            Err(e) => eprintln!("Dropping peer {}: {}", peer, e),
+        }
+    }
+    let piece = piece.ok_or_else(|| io::Error::other("No peer could provide the piece"))?;
 
-    //? Send interested message
-    stream
-        .write()
-        .await
-        .write_all(&[0, 0, 0, 1, 2])
+    tokio::fs::write(output_path, piece.clone())
         .await
-        .expect("Failed to send message");
-
-    //? Unchoke message
-    let message = receive_message(&stream).await?;
-    assert!(message.id == 1);
+        .expect("Failed to write piece");
 
-    let metadata_info = &metadata.read().await.info;
-    let piece_index = piece_index as u32;
-    let pieces_count = piece_hashes.len() as u32;
-    let piece_length = if piece_index == pieces_count - 1 {
-        metadata_info.length - (piece_index * metadata_info.piece_length)
-    } else {
-        metadata_info.piece_length
-    };
+    Ok(())
+}
 
-    //? Piece blocks messages to send
-    let piece_blocks_messages = get_piece_blocks_messages(piece_index, piece_length);
+async fn fetch_piece(
+    metadata: &RwLock<info::Metadata>,
+    peer: &str,
+    piece_index: usize,
+    piece_hash: &str,
+    piece_count: usize,
+    timeouts: &Timeouts,
+) -> Result<Vec<u8>, io::Error> {
+    let connection = handshake::get_handshake(metadata, peer, timeouts)
+        .await
+        .map_err(io::Error::other)?;
+    connection.set_piece_count(piece_count);
 
-    //? Received piece blocks
-    let piece_blocks = receive_piece_blocks(&stream, piece_blocks_messages).await;
+    //? Send interested message
+    send_message(&connection, &PeerMessage::Interested).await?;
+    wait_for_unchoke(&connection, timeouts.request).await?;
 
-    let piece = combine_blocks_into_piece(piece_blocks, piece_length, piece_index, piece_hash);
+    if !connection.has_piece(piece_index) {
+        return Err(io::Error::other("Peer does not have piece"));
+    }
 
-    tokio::fs::write(output_path, piece.clone())
+    let piece_index = piece_index as u32;
+    let piece_length = metadata
+        .read()
         .await
-        .expect("Failed to write piece");
+        .info
+        .piece_size(piece_index)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Piece index out of range"))?;
+
+    //? Received piece blocks, starting over whenever the peer chokes us halfway.
+    //? A single piece is not worth limiting
+    let unlimited = RateLimit::default();
+    let piece_blocks = loop {
+        let blocks = receive_piece_blocks(
+            &connection,
+            piece_index,
+            piece_length,
+            &unlimited,
+            timeouts.request,
+        );
+        match blocks.await {
+            Err(e) if is_interrupted(&e) => wait_for_unchoke(&connection, timeouts.request).await?,
+            result => break result?,
+        }
+    };
 
-    Ok(())
+    combine_blocks_into_piece(piece_blocks, piece_length, piece_index, piece_hash).await
 }
 
-pub async fn get_bitfield(stream: &RwLock<TcpStream>) -> Vec<bool> {
-    //? Bitfield message
-    let message = receive_message(stream)
-        .await
-        .expect("Failed to receive bitfield message");
-    assert!(message.id == 5);
-
-    message
-        .payload
-        .iter()
-        .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
-        .collect()
+/// Applies control messages to the peer state, handing back the ones the caller has to act on
+pub fn handle_message(connection: &PeerConnection, message: PeerMessage) -> Option<PeerMessage> {
+    let mut state = connection.state.lock().unwrap();
+    match message {
+        PeerMessage::KeepAlive => {}
+        PeerMessage::Choke => state.peer_choking = true,
+        PeerMessage::Unchoke => state.peer_choking = false,
+        PeerMessage::Interested => state.peer_interested = true,
+        PeerMessage::NotInterested => state.peer_interested = false,
+        PeerMessage::Have { piece_index } => {
+            //? Pieces past the end of the torrent do not exist, there is nothing to record
+            if let Some(has) = state.bitfield.get_mut(piece_index as usize) {
+                *has = true;
+            }
+        }
+        PeerMessage::Bitfield(bitfield) => {
+            //? Keep haves that came in before the bitfield, and drop the spare bits at the end
+            let decoded = message::decode_bitfield(&bitfield);
+            for (has, bit) in state.bitfield.iter_mut().zip(decoded) {
+                *has |= bit;
+            }
+        }
+        message => return Some(message),
+    }
+    None
 }
 
-pub fn combine_blocks_into_piece(
+/// Reads messages until the peer unchokes us, recording its bitfield and haves on the way;
+/// gives up if the peer goes silent for `request_timeout`
+pub async fn wait_for_unchoke(
+    connection: &PeerConnection,
+    request_timeout: Duration,
+) -> Result<(), io::Error> {
+    while connection.is_choking() {
+        let message = receive_message_within(connection, request_timeout).await?;
+        handle_message(connection, message);
+    }
+    Ok(())
+}
+
+pub async fn combine_blocks_into_piece(
     piece_blocks: Vec<Option<Block>>,
-    piece_length: u32,
+    piece_length: u64,
     piece_index: u32,
     piece_hash: &str,
-) -> Vec<u8> {
+) -> Result<Vec<u8>, io::Error> {
     //? Combine piece blocks into piece=
-    let mut piece = vec![0; piece_length as usize];
-    for block_enum in piece_blocks.iter().enumerate() {
-        let block = block_enum.1.clone().unwrap_or(Block {
-            piece_index,
-            begin: block_enum.0 as u32 * BLOCK_SIZE,
-            block: vec![0; BLOCK_SIZE as usize],
-        });
+    let mut piece = vec![0; usize::try_from(piece_length).map_err(piece_too_large)?];
+    for (block_index, block) in piece_blocks.into_iter().enumerate() {
+        let block = block.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Block {} of piece {} is missing", block_index, piece_index),
+            )
+        })?;
         let start = block.begin as usize;
         let mut block = block.block;
         let mut end = start + block.len();
@@ -103,144 +175,196 @@ pub fn combine_blocks_into_piece(
         piece[start..end].copy_from_slice(&block);
     }
 
-    //? Hash piece
-    let mut hasher = Sha1::new();
-    hasher.update(&piece);
-    let hash: [u8; 20] = hasher.finalize().into();
-    let hash = hex::encode(hash);
-
-    assert_eq!(hash, *piece_hash, "Hashes do not match");
+    let (piece, matches) = hash_piece(piece, piece_hash.to_string()).await;
+    if !matches {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            HashMismatch(piece_index),
+        ));
+    }
 
-    piece
+    Ok(piece)
 }
 
+/// Requests the blocks and collects them, as many at a time as the peer keeps up with.
+/// Reading slows down to stay within `limit`, the peer has `request_timeout` for each block
 pub async fn receive_piece_blocks(
-    stream: &RwLock<TcpStream>,
-    piece_blocks_messages: Vec<Vec<u8>>,
-) -> Vec<Option<Block>> {
-    //? Save the number of chunks
-    let number_of_chunks = piece_blocks_messages.len() as u32;
-    let piece_blocks_messages = &mut piece_blocks_messages.into_iter();
-
-    //? Send first 5 requests
-    for _ in 0..5 {
-        if let Some(message) = piece_blocks_messages.next() {
-            stream
-                .write()
-                .await
-                .write_all(&message)
-                .await
-                .expect("Failed to send message");
+    connection: &PeerConnection,
+    piece_index: u32,
+    piece_length: u64,
+    limit: &RateLimit,
+    request_timeout: Duration,
+) -> Result<Vec<Option<Block>>, io::Error> {
+    let mut pipeline = Pipeline::default();
+    pipeline.add_piece(piece_index, piece_length)?;
+
+    loop {
+        if !connection.is_choking() {
+            let depth = pipeline.depth(connection.reqq());
+            for request in pipeline.requests(depth) {
+                send_message(connection, &request).await?;
+            }
         }
-    }
 
-    let mut blocks = vec![Option::None; number_of_chunks as usize];
-    for _ in 0..number_of_chunks {
-        let message = match receive_message(stream).await {
-            Ok(message) => {
-                if message.id != 7 {
-                    continue;
+        let message = receive_message_within(connection, request_timeout).await?;
+        if let Some(PeerMessage::Piece {
+            piece_index,
+            begin,
+            block,
+        }) = handle_message(connection, message)
+        {
+            record_block(connection, limit, block.len()).await;
+            if pipeline.receive(piece_index, begin, block) {
+                if let Some((_, blocks)) = pipeline.finished() {
+                    return Ok(blocks);
                 }
-                message
-            }
-            Err(e) => {
-                println!("Failed to receive message {}", e);
-                continue;
             }
-        };
-
-        let block = Block {
-            piece_index: bytes_to_u32(&message.payload[0..4]),
-            begin: bytes_to_u32(&message.payload[4..8]),
-            block: message.payload[8..].to_vec(),
-        };
-
-        let block_index = (block.begin as f64 / BLOCK_SIZE as f64).ceil() as u32;
-        assert!(block_index < number_of_chunks, "Block index out of range");
-
-        //? Save block
-        blocks[block_index as usize] = Some(block.clone());
-
-        //? Send next request to always have 5 requests in flight
-        if let Some(message) = piece_blocks_messages.next() {
-            stream
-                .write()
-                .await
-                .write_all(&message)
-                .await
-                .expect("Failed to send message");
         }
+
+        //? A choke discards everything we asked for, let the caller decide whether to wait
+        if connection.is_choking() {
+            return Err(interrupted("Peer choked us"));
+        }
+    }
+}
+
+/// Counts a block the peer sent towards what we downloaded from it, waiting for `limit`
+/// before reading on; the peer backs off once our receive window fills up
+pub async fn record_block(connection: &PeerConnection, limit: &RateLimit, length: usize) {
+    {
+        let mut state = connection.state.lock().unwrap();
+        state.downloaded += length as u64;
+        state.last_block = Instant::now();
     }
-    blocks
+    limit.acquire(length).await;
 }
 
-pub fn get_piece_blocks_messages(piece_index: u32, piece_length: u32) -> Vec<Vec<u8>> {
-    let chunks: u32 = (piece_length as f64 / BLOCK_SIZE as f64).ceil() as u32;
+pub fn get_piece_blocks_messages(
+    piece_index: u32,
+    piece_length: u64,
+) -> Result<Vec<PeerMessage>, io::Error> {
+    //? Block offsets travel as u32 on the wire, so a single piece has to fit in one
+    let piece_length = u32::try_from(piece_length).map_err(piece_too_large)?;
+    let chunks: u32 = piece_length.div_ceil(BLOCK_SIZE);
 
     let mut messages_to_send = Vec::new();
 
     for i in 0..chunks {
-        let u32_payload = &[
+        messages_to_send.push(PeerMessage::Request {
             piece_index,
-            i * BLOCK_SIZE,
-            if i == chunks - 1 {
+            begin: i * BLOCK_SIZE,
+            length: if i == chunks - 1 {
                 piece_length - (i * BLOCK_SIZE)
             } else {
                 BLOCK_SIZE
             },
-        ];
-        let payload = u32_slice_to_bytes(u32_payload);
-        let mut message = u32_slice_to_bytes(&[payload.len() as u32]);
-        message.push(6);
-        message.extend(payload);
-        messages_to_send.push(message);
+        });
     }
 
-    messages_to_send
+    Ok(messages_to_send)
 }
 
-pub fn u32_slice_to_bytes(input: &[u32]) -> Vec<u8> {
-    let mut output = Vec::with_capacity(input.len() * 4);
-    for &num in input {
-        output.push((num >> 24) as u8);
-        output.push((num >> 16) as u8);
-        output.push((num >> 8) as u8);
-        output.push(num as u8);
-    }
-    output
+/// Whether the piece hashes to the hex-encoded SHA-1 from the torrent
+pub fn piece_matches(piece: &[u8], piece_hash: &str) -> bool {
+    let mut hasher = Sha1::new();
+    hasher.update(piece);
+    let hash: [u8; 20] = hasher.finalize().into();
+    hex::encode(hash) == piece_hash
 }
 
-pub fn bytes_to_u32(input: &[u8]) -> u32 {
-    ((input[0] as u32) << 24)
-        | ((input[1] as u32) << 16)
-        | ((input[2] as u32) << 8)
-        | (input[3] as u32)
+/// Checks the piece on the rayon thread pool, so hashing never stalls the tokio runtime;
+/// the piece is handed back along with the result
+pub async fn hash_piece(piece: Vec<u8>, piece_hash: String) -> (Vec<u8>, bool) {
+    spawn_hash(piece, piece_hash)
+        .await
+        .expect("Hashing thread panicked")
 }
 
-pub async fn receive_message(stream: &RwLock<TcpStream>) -> Result<Message, std::io::Error> {
-    let message_length: u32 = stream.write().await.read_u32().await?;
-    let message_id = stream.write().await.read_u8().await?;
-
-    let msg = if message_length > 1 {
-        let mut msg = vec![0; message_length as usize - 1];
-        stream
-            .write()
-            .await
-            .read_exact(&mut msg)
-            .await
-            .expect("Failed to read from server");
-        msg
-    } else {
-        Vec::new()
-    };
+/// Starts checking the piece on the rayon thread pool right away, for hashing many at once
+pub fn spawn_hash(piece: Vec<u8>, piece_hash: String) -> oneshot::Receiver<(Vec<u8>, bool)> {
+    let (sender, receiver) = oneshot::channel();
+    rayon::spawn(move || {
+        let matches = piece_matches(&piece, &piece_hash);
+        let _ = sender.send((piece, matches));
+    });
+    receiver
+}
 
-    let message = Message {
-        length: message_length,
-        id: message_id,
-        payload: msg,
-    };
+/// A piece did not match its hash, the peer that sent it gave us bad data
+#[derive(Debug, Error)]
+#[error("Piece {0} does not match its hash")]
+pub struct HashMismatch(pub u32);
+
+pub fn is_hash_mismatch(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|e| e.is::<HashMismatch>())
+}
 
-    Ok(message)
+/// Returned when a piece is cut short by a choke or by another peer finishing it first,
+/// the connection itself is fine
+fn interrupted(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, reason)
+}
+
+pub fn is_interrupted(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Interrupted
+}
+
+fn piece_too_large<E>(_: E) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "Piece length does not fit in memory",
+    )
+}
+
+pub async fn send_message(
+    connection: &PeerConnection,
+    message: &PeerMessage,
+) -> Result<(), std::io::Error> {
+    connection
+        .outbound
+        .send(message.encode())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Connection closed"))
+}
+
+/// Next message from the peer, with its requests already served if we upload to it
+pub async fn receive_message(connection: &PeerConnection) -> Result<PeerMessage, std::io::Error> {
+    loop {
+        let message = next_message(connection).await?;
+        match connection.uploader.get() {
+            Some(uploader) => {
+                if let Some(message) = uploader.serve(connection, message) {
+                    return Ok(message);
+                }
+            }
+            None => return Ok(message),
+        }
+    }
+}
+
+/// Like `receive_message`, failing with `TimedOut` if nothing comes in for `limit`
+pub async fn receive_message_within(
+    connection: &PeerConnection,
+    limit: Duration,
+) -> Result<PeerMessage, std::io::Error> {
+    timeout(limit, receive_message(connection))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "Peer stopped answering"))?
+}
+
+/// Next message from the peer as it arrived. Unlike `receive_message` this is cancel safe,
+/// the reader task keeps whatever was not taken yet, so it can race other futures in `select!`
+pub async fn next_message(connection: &PeerConnection) -> Result<PeerMessage, std::io::Error> {
+    let mut inbound = connection.inbound.lock().await;
+    match inbound.recv().await {
+        Some(message) => message,
+        //? The reader task already handed out the error that stopped it
+        None => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
+
+pub async fn read_message(stream: &mut TcpStream) -> Result<PeerMessage, std::io::Error> {
+    PeerMessage::read(stream).await
 }
 
 #[derive(Debug, Clone)]
@@ -250,20 +374,3 @@ pub struct Block {
     pub begin: u32,
     pub block: Vec<u8>,
 }
-
-#[derive(Debug)]
-pub struct Message {
-    pub length: u32,
-    pub id: u8,
-    pub payload: Vec<u8>,
-}
-
-impl Display for Message {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{{ length: {}, id: {}, payload: {:?} }}",
-            self.length, self.id, self.payload
-        )
-    }
-}
@@ -0,0 +1,65 @@
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::collections::HashMap;
+
+/// Extended message ID of the extension handshake itself
+pub const EXTENDED_HANDSHAKE_ID: u8 = 0;
+
+/// Reserved byte and bit set in the handshake to advertise the extension protocol
+pub const EXTENSION_PROTOCOL_BYTE: usize = 5;
+pub const EXTENSION_PROTOCOL_BIT: u8 = 0x10;
+
+/// ID we ask peers to use when sending us `ut_metadata` (BEP 9) messages
+pub const UT_METADATA_ID: u8 = 1;
+/// The info dictionary is exchanged in pieces of this size, only the last one is shorter
+pub const METADATA_PIECE_SIZE: usize = 16 * 1_024;
+
+pub const METADATA_REQUEST: i64 = 0;
+pub const METADATA_DATA: i64 = 1;
+pub const METADATA_REJECT: i64 = 2;
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtendedHandshake {
+    /// Extension names mapped to the message IDs the sender wants to receive them with
+    #[serde(default)]
+    pub m: HashMap<String, i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub v: Option<ByteBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reqq: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    /// The extended handshake this client sends
+    pub fn ours() -> Self {
+        Self {
+            m: HashMap::from([("ut_metadata".to_string(), UT_METADATA_ID as i64)]),
+            v: Some(ByteBuf::from(
+                concat!("bittorrent-rust ", env!("CARGO_PKG_VERSION")).as_bytes(),
+            )),
+            ..Default::default()
+        }
+    }
+
+    /// ID to send the named extension's messages with, `None` if the peer does not support it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&id) if id > 0 => u8::try_from(id).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MetadataMessage {
+    pub msg_type: i64,
+    pub piece: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total_size: Option<i64>,
+}
+
+pub fn supports_extension_protocol(reserved: &[u8; 8]) -> bool {
+    reserved[EXTENSION_PROTOCOL_BYTE] & EXTENSION_PROTOCOL_BIT != 0
+}
@@ -1,43 +1,406 @@
+use bytes::BytesMut;
+use serde_bencode::{from_bytes, to_bytes};
+use std::collections::VecDeque;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use thiserror::Error;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::RwLock;
+use tokio::sync::{mpsc, Notify, RwLock};
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
 
+use crate::download_piece;
+use crate::extension::{self, ExtendedHandshake};
 use crate::info::Metadata;
+use crate::message::PeerMessage;
+use crate::upload::Uploader;
 
-pub async fn get_handshake(metadata: &RwLock<Metadata>, peer: &str) -> (String, RwLock<TcpStream>) {
-    let handshake = construct_handshake(metadata).await;
+const PROTOCOL: &[u8; 19] = b"BitTorrent protocol";
+/// Defaults for how long a peer gets to accept our connection, to exchange both
+/// handshakes, and to answer a request
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Messages buffered each way between the connection's tasks and whoever uses it,
+/// a full channel holds off reading from the peer or sending to it
+const CHANNEL_CAPACITY: usize = 32;
 
-    let mut stream = TcpStream::connect(peer)
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("Connection refused")]
+    ConnectionRefused,
+    #[error("Connection timed out")]
+    ConnectTimeout,
+    #[error("Handshake timed out")]
+    Timeout,
+    #[error("Peer does not speak the BitTorrent protocol")]
+    WrongProtocol,
+    #[error("Peer answered for a different info-hash")]
+    InfoHashMismatch,
+    #[error("Peer asked for a torrent we do not have")]
+    UnknownInfoHash,
+    #[error("Invalid extended handshake: {0}")]
+    InvalidExtendedHandshake(serde_bencode::Error),
+    #[error("Handshake failed: {0}")]
+    Io(io::Error),
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::ConnectionRefused => HandshakeError::ConnectionRefused,
+            io::ErrorKind::TimedOut => HandshakeError::Timeout,
+            _ => HandshakeError::Io(e),
+        }
+    }
+}
+
+/// How long to wait on a peer before giving up on it
+#[derive(Debug, Clone, Copy)]
+pub struct Timeouts {
+    pub connect: Duration,
+    pub handshake: Duration,
+    /// For a requested block to arrive, or any message while we wait on the peer
+    pub request: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            connect: CONNECT_TIMEOUT,
+            handshake: HANDSHAKE_TIMEOUT,
+            request: REQUEST_TIMEOUT,
+        }
+    }
+}
+
+/// An established peer connection, together with what the peer told us about itself
+pub struct PeerConnection {
+    /// Where the connection goes, the port is ephemeral for peers that connected to us
+    pub address: SocketAddr,
+    pub peer_id: [u8; 20],
+    pub reserved: [u8; 8],
+    /// The peer's extended handshake (BEP 10), `None` if it does not speak the extension protocol
+    pub extensions: Option<ExtendedHandshake>,
+    /// Messages as the reader task parsed them, ending with the error that stopped it
+    pub inbound: tokio::sync::Mutex<mpsc::Receiver<io::Result<PeerMessage>>>,
+    /// Encoded messages for the writer task to put on the stream
+    pub outbound: mpsc::Sender<Vec<u8>>,
+    reader: JoinHandle<()>,
+    pub state: Mutex<PeerState>,
+    /// Woken when the peer asks for another block, see `PeerState::requested`
+    pub requested: Notify,
+    /// Serves the peer's requests, set once we have something to upload
+    pub uploader: OnceLock<Arc<Uploader>>,
+}
+
+/// What the peer told us through control messages, see `download_piece::handle_message`
+#[derive(Debug)]
+pub struct PeerState {
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+    pub am_choking: bool,
+    pub am_interested: bool,
+    /// Set by the choker when the peer lets us download but has not sent anything for a while
+    pub snubbed: bool,
+    /// Piece data received from the peer and sent to it, in bytes
+    pub downloaded: u64,
+    pub uploaded: u64,
+    /// When the peer last sent us a block we asked for, or when we connected
+    pub last_block: Instant,
+    /// Pieces we told the peer about, with our bitfield or a `have`
+    pub announced: Vec<bool>,
+    /// Pieces the peer has, empty until `set_piece_count` tells how many the torrent has
+    pub bitfield: Vec<bool>,
+    /// Blocks the peer asked us for as (piece index, begin, length), in order, waiting for
+    /// `Uploader::upload` to send them
+    pub requested: VecDeque<(u32, u32, u32)>,
+}
+
+impl Default for PeerState {
+    fn default() -> Self {
+        //? Every connection starts out choked
+        Self {
+            peer_choking: true,
+            peer_interested: false,
+            am_choking: true,
+            am_interested: false,
+            snubbed: false,
+            downloaded: 0,
+            uploaded: 0,
+            last_block: Instant::now(),
+            announced: Vec::new(),
+            bitfield: Vec::new(),
+            requested: VecDeque::new(),
+        }
+    }
+}
+
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        //? The writer stops once the last message is out, the reader could wait on the peer forever
+        self.reader.abort();
+    }
+}
+
+impl PeerConnection {
+    pub fn is_choking(&self) -> bool {
+        self.state.lock().unwrap().peer_choking
+    }
+
+    pub fn has_piece(&self, piece_index: usize) -> bool {
+        let state = self.state.lock().unwrap();
+        state.bitfield.get(piece_index).copied().unwrap_or(false)
+    }
+
+    pub fn bitfield(&self) -> Vec<bool> {
+        self.state.lock().unwrap().bitfield.clone()
+    }
+
+    /// Sizes the peer's bitfield to the torrent, `have`s past its last piece are ignored
+    pub fn set_piece_count(&self, piece_count: usize) {
+        self.state.lock().unwrap().bitfield = vec![false; piece_count];
+    }
+
+    pub fn peer_id_hex(&self) -> String {
+        hex::encode(self.peer_id)
+    }
+
+    pub fn supports_extension_protocol(&self) -> bool {
+        extension::supports_extension_protocol(&self.reserved)
+    }
+
+    /// ID to send the named extension's messages with, `None` if the peer does not support it
+    pub fn extension_id(&self, name: &str) -> Option<u8> {
+        self.extensions.as_ref()?.extension_id(name)
+    }
+
+    /// Client name and version the peer advertised (`v`)
+    pub fn client(&self) -> Option<String> {
+        let v = self.extensions.as_ref()?.v.as_ref()?;
+        Some(String::from_utf8_lossy(v).to_string())
+    }
+
+    /// Number of outstanding requests the peer is willing to queue (`reqq`)
+    pub fn reqq(&self) -> Option<usize> {
+        let reqq = self.extensions.as_ref()?.reqq?;
+        usize::try_from(reqq).ok().filter(|&reqq| reqq > 0)
+    }
+
+    /// Size of the info dictionary the peer can send over `ut_metadata`
+    pub fn metadata_size(&self) -> Option<usize> {
+        let metadata_size = self.extensions.as_ref()?.metadata_size?;
+        usize::try_from(metadata_size).ok()
+    }
+}
+
+pub async fn get_handshake(
+    metadata: &RwLock<Metadata>,
+    peer: &str,
+    timeouts: &Timeouts,
+) -> Result<PeerConnection, HandshakeError> {
+    let info_hash = metadata.read().await.info.get_hash();
+    handshake(&info_hash, peer, timeouts).await
+}
+
+/// Connects to the peer and exchanges the BitTorrent handshake for the given info-hash,
+/// followed by the extended handshake when both sides support the extension protocol
+pub async fn handshake(
+    info_hash: &[u8; 20],
+    peer: &str,
+    timeouts: &Timeouts,
+) -> Result<PeerConnection, HandshakeError> {
+    let stream = timeout(timeouts.connect, TcpStream::connect(peer))
         .await
-        .expect("Could not connect to server");
-    stream
-        .write_all(&handshake)
+        .map_err(|_| HandshakeError::ConnectTimeout)??;
+
+    timeout(timeouts.handshake, establish(info_hash, stream))
         .await
-        .expect("Failed to send message");
+        .map_err(|_| HandshakeError::Timeout)?
+}
 
-    let mut buffer = [0; 68];
-    stream
-        .read_exact(&mut buffer)
+async fn establish(
+    info_hash: &[u8; 20],
+    mut stream: TcpStream,
+) -> Result<PeerConnection, HandshakeError> {
+    let handshake = construct_handshake(info_hash);
+    stream.write_all(&handshake).await?;
+
+    let (reserved, peer_info_hash, peer_id) = read_handshake(&mut stream).await?;
+    if peer_info_hash != *info_hash {
+        return Err(HandshakeError::InfoHashMismatch);
+    }
+
+    connect(stream, reserved, peer_id).await
+}
+
+/// Answers the handshake of a peer that connected to us, if `is_known` says we have the
+/// torrent it asks for. Returns the connection along with that torrent's info-hash
+pub async fn accept(
+    mut stream: TcpStream,
+    timeouts: &Timeouts,
+    is_known: impl Fn(&[u8; 20]) -> bool,
+) -> Result<(PeerConnection, [u8; 20]), HandshakeError> {
+    let accepting = async {
+        //? The connecting side speaks first, we answer for the torrent it names
+        let (reserved, info_hash, peer_id) = read_handshake(&mut stream).await?;
+        if !is_known(&info_hash) {
+            return Err(HandshakeError::UnknownInfoHash);
+        }
+        stream.write_all(&construct_handshake(&info_hash)).await?;
+
+        Ok((connect(stream, reserved, peer_id).await?, info_hash))
+    };
+    timeout(timeouts.handshake, accepting)
         .await
-        .expect("Failed to read from server");
+        .map_err(|_| HandshakeError::Timeout)?
+}
+
+/// Reads the peer's handshake, returning its reserved bytes, info-hash and peer ID
+async fn read_handshake(
+    stream: &mut TcpStream,
+) -> Result<([u8; 8], [u8; 20], [u8; 20]), HandshakeError> {
+    //? Check the protocol before reading the rest, a different protocol may send fewer bytes
+    let protocol_length = stream.read_u8().await?;
+    if protocol_length as usize != PROTOCOL.len() {
+        return Err(HandshakeError::WrongProtocol);
+    }
+
+    let mut buffer = [0; 67];
+    stream.read_exact(&mut buffer).await?;
+
+    if buffer[..19] != PROTOCOL[..] {
+        return Err(HandshakeError::WrongProtocol);
+    }
+
+    let mut reserved = [0; 8];
+    reserved.copy_from_slice(&buffer[19..27]);
+    let mut info_hash = [0; 20];
+    info_hash.copy_from_slice(&buffer[27..47]);
+    let mut peer_id = [0; 20];
+    peer_id.copy_from_slice(&buffer[47..]);
+    Ok((reserved, info_hash, peer_id))
+}
+
+/// Sets up the connection once both handshakes went through
+async fn connect(
+    mut stream: TcpStream,
+    reserved: [u8; 8],
+    peer_id: [u8; 20],
+) -> Result<PeerConnection, HandshakeError> {
+    let address = stream.peer_addr()?;
+    let mut pending = VecDeque::new();
+    let extensions = if extension::supports_extension_protocol(&reserved) {
+        Some(exchange_extended_handshakes(&mut stream, &mut pending).await?)
+    } else {
+        None
+    };
+
+    //? Reading and writing on their own, so we can send while waiting for the peer
+    let (read_half, write_half) = stream.into_split();
+    let (inbound_sender, inbound) = mpsc::channel(CHANNEL_CAPACITY);
+    let (outbound, outbound_receiver) = mpsc::channel(CHANNEL_CAPACITY);
+    let reader = tokio::spawn(read_messages(read_half, pending, inbound_sender));
+    tokio::spawn(write_messages(write_half, outbound_receiver));
+
+    Ok(PeerConnection {
+        address,
+        peer_id,
+        reserved,
+        extensions,
+        inbound: tokio::sync::Mutex::new(inbound),
+        outbound,
+        reader,
+        state: Mutex::new(PeerState::default()),
+        requested: Notify::new(),
+        uploader: OnceLock::new(),
+    })
+}
+
+/// Parses messages off the stream until it fails, handing out the ones that came in during
+/// the handshake first
+async fn read_messages(
+    mut stream: OwnedReadHalf,
+    pending: VecDeque<PeerMessage>,
+    inbound: mpsc::Sender<io::Result<PeerMessage>>,
+) {
+    for message in pending {
+        if inbound.send(Ok(message)).await.is_err() {
+            return;
+        }
+    }
+
+    let mut buffer = BytesMut::new();
+    loop {
+        let message = match PeerMessage::parse(&mut buffer) {
+            Ok(Some(message)) => Ok(message),
+            Ok(None) => match stream.read_buf(&mut buffer).await {
+                Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(_) => continue,
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
 
-    (
-        buffer[48..]
-            .iter()
-            .map(|byte| format!("{:02x}", byte))
-            .collect::<Vec<String>>()
-            .join(""),
-        RwLock::new(stream),
-    )
+        let failed = message.is_err();
+        if inbound.send(message).await.is_err() || failed {
+            return;
+        }
+    }
 }
 
-async fn construct_handshake(metadata: &RwLock<Metadata>) -> Vec<u8> {
+/// Writes out messages in the order they were sent, until the connection is dropped or the
+/// stream fails
+async fn write_messages(mut stream: OwnedWriteHalf, mut outbound: mpsc::Receiver<Vec<u8>>) {
+    while let Some(message) = outbound.recv().await {
+        if stream.write_all(&message).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn exchange_extended_handshakes(
+    stream: &mut TcpStream,
+    pending: &mut VecDeque<PeerMessage>,
+) -> Result<ExtendedHandshake, HandshakeError> {
+    let our_handshake =
+        to_bytes(&ExtendedHandshake::ours()).map_err(HandshakeError::InvalidExtendedHandshake)?;
+    stream
+        .write_all(
+            &PeerMessage::Extended {
+                id: extension::EXTENDED_HANDSHAKE_ID,
+                payload: our_handshake,
+            }
+            .encode(),
+        )
+        .await?;
+
+    //? The peer may send its bitfield and the like first, keep those for whoever reads next
+    loop {
+        match download_piece::read_message(stream).await? {
+            PeerMessage::Extended { id, payload } if id == extension::EXTENDED_HANDSHAKE_ID => {
+                return from_bytes::<ExtendedHandshake>(&payload)
+                    .map_err(HandshakeError::InvalidExtendedHandshake);
+            }
+            message => pending.push_back(message),
+        }
+    }
+}
+
+fn construct_handshake(info_hash: &[u8; 20]) -> Vec<u8> {
+    let mut reserved = [0; 8];
+    reserved[extension::EXTENSION_PROTOCOL_BYTE] |= extension::EXTENSION_PROTOCOL_BIT;
+
     let mut handshake = Vec::new();
-    handshake.push(19);
-    handshake.extend(b"BitTorrent protocol");
-    handshake.extend(vec![0; 8]);
-    handshake.extend(metadata.write().await.info.get_hash());
+    handshake.push(PROTOCOL.len() as u8);
+    handshake.extend(PROTOCOL);
+    handshake.extend(reserved);
+    handshake.extend(info_hash);
     handshake.extend(b"21372137696921372137");
     handshake
 }
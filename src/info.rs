@@ -1,39 +1,150 @@
 use serde::{Deserialize, Serialize};
-use serde_bencode::{from_bytes, to_bytes};
+use serde_bencode::from_bytes;
 use serde_bytes::ByteBuf;
 use sha1::{Digest, Sha1};
 use std::fs;
 
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::decode;
+use crate::handshake::Timeouts;
+use crate::magnet::{self, Magnet, MagnetError};
+use crate::random;
+
+/// A torrent as given on the command line, either a .torrent file or a magnet link
+#[derive(Debug, Clone)]
+pub enum TorrentSource {
+    File(PathBuf),
+    Magnet(Magnet),
+}
+
+impl FromStr for TorrentSource {
+    type Err = MagnetError;
+
+    fn from_str(source: &str) -> Result<Self, Self::Err> {
+        if source.starts_with("magnet:") {
+            Ok(TorrentSource::Magnet(source.parse()?))
+        } else {
+            Ok(TorrentSource::File(PathBuf::from(source)))
+        }
+    }
+}
+
+impl Display for TorrentSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TorrentSource::File(path) => write!(f, "{}", path.display()),
+            TorrentSource::Magnet(magnet) => match &magnet.display_name {
+                Some(name) => write!(f, "{}", name),
+                None => write!(f, "{}", hex::encode(magnet.info_hash)),
+            },
+        }
+    }
+}
+
+/// Reads the torrent file, or fetches the metadata from peers within `timeouts` for a magnet link
+pub async fn get_metadata(source: &TorrentSource, timeouts: &Timeouts) -> Metadata {
+    match source {
+        TorrentSource::File(path) => get_info(path),
+        TorrentSource::Magnet(magnet) => magnet::get_metadata(magnet, timeouts)
+            .await
+            .expect("Failed to fetch metadata for magnet link"),
+    }
+}
 
 pub fn get_info(path: &Path) -> Metadata {
     let contents: Vec<u8> = fs::read(path).unwrap();
-    from_bytes::<Metadata>(&contents).unwrap()
+    let mut metadata = from_bytes::<Metadata>(&contents).unwrap();
+    metadata.info.check_length().unwrap();
+
+    //? Keep the info dictionary exactly as it was encoded, re-serializing would drop unknown keys
+    let info_span =
+        decode::dict_value_span(&contents, b"info").expect("Torrent file has no info dictionary");
+    metadata.info.raw = contents[info_span].to_vec();
+    metadata.init_tracker_tiers();
+
+    metadata
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Metadata {
-    pub announce: String,
+    pub announce: Option<String>,
+    /// Tracker tiers (BEP 12), tried in order. Falls back to a single tier holding `announce`.
+    #[serde(rename = "announce-list", default)]
+    pub announce_list: Vec<Vec<String>>,
     pub info: Info,
 }
 
+impl Metadata {
+    /// Builds the tracker tiers and shuffles every tier once, as BEP 12 asks clients to do on load
+    pub fn init_tracker_tiers(&mut self) {
+        self.announce_list.retain(|tier| !tier.is_empty());
+        if self.announce_list.is_empty() {
+            if let Some(announce) = &self.announce {
+                self.announce_list.push(vec![announce.clone()]);
+            }
+        }
+
+        for tier in self.announce_list.iter_mut() {
+            random::shuffle(tier);
+        }
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Serialize, Debug, Deserialize, Clone)]
 pub struct Info {
     pub name: String,
     #[serde(rename = "piece length")]
-    pub piece_length: u32,
+    pub piece_length: u64,
     pub pieces: ByteBuf,
-    pub length: u32,
+    #[serde(flatten)]
+    pub keys: Keys,
+    /// Bencoded info dictionary as found in the torrent, the info-hash is taken over these bytes
+    #[serde(skip)]
+    pub raw: Vec<u8>,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Keys {
+    SingleFile { length: u64 },
+    MultiFile { files: Vec<File> },
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone)]
+pub struct File {
+    pub length: u64,
+    pub path: Vec<String>,
 }
 
 impl Info {
-    pub fn get_hash(&self) -> [u8; 20] {
-        let bencoded_info = to_bytes(&self).unwrap();
+    /// Parses a bencoded info dictionary, keeping its bytes for the info-hash
+    pub fn from_raw(raw: Vec<u8>) -> Result<Self, serde_bencode::Error> {
+        let mut info = from_bytes::<Info>(&raw)?;
+        info.check_length()?;
+        info.raw = raw;
+        Ok(info)
+    }
+
+    /// Refuses files adding up to more than a `u64` holds, so `length` can not overflow later
+    fn check_length(&self) -> Result<(), serde_bencode::Error> {
+        if let Keys::MultiFile { files } = &self.keys {
+            files
+                .iter()
+                .try_fold(0u64, |total, file| total.checked_add(file.length))
+                .ok_or_else(|| {
+                    serde_bencode::Error::Custom("Torrent length overflows u64".into())
+                })?;
+        }
+        Ok(())
+    }
 
+    pub fn get_hash(&self) -> [u8; 20] {
         let mut hasher = Sha1::new();
-        hasher.update(bencoded_info);
+        hasher.update(&self.raw);
         let hash: [u8; 20] = hasher.finalize().into();
         hash
     }
@@ -56,6 +167,41 @@ impl Info {
 
         piece_hashes
     }
+
+    /// Total number of bytes in the torrent, summed over all files; the sum was checked
+    /// for overflow when the torrent was parsed
+    pub fn length(&self) -> u64 {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|file| file.length).sum(),
+        }
+    }
+
+    /// Offset of the first byte of the piece within the whole torrent
+    pub fn piece_offset(&self, piece_index: u32) -> Option<u64> {
+        (piece_index as u64).checked_mul(self.piece_length)
+    }
+
+    /// Number of bytes in the piece, only the last one can be shorter than `piece_length`
+    pub fn piece_size(&self, piece_index: u32) -> Option<u64> {
+        let remaining = self.length().checked_sub(self.piece_offset(piece_index)?)?;
+        match remaining.min(self.piece_length) {
+            0 => None,
+            size => Some(size),
+        }
+    }
+
+    /// Files of the torrent as (relative path, length) pairs, in the order their
+    /// bytes appear in the pieces. A single-file torrent yields one entry named after the torrent.
+    pub fn files(&self) -> Vec<(PathBuf, u64)> {
+        match &self.keys {
+            Keys::SingleFile { length } => vec![(PathBuf::from(&self.name), *length)],
+            Keys::MultiFile { files } => files
+                .iter()
+                .map(|file| (file.path.iter().collect::<PathBuf>(), file.length))
+                .collect(),
+        }
+    }
 }
 
 impl Display for Metadata {
@@ -63,8 +209,8 @@ impl Display for Metadata {
         write!(
             f,
             "Tracker URL: {}\nLength: {}\nInfo Hash: {}\nPiece Length: {}\nPiece Hashes:\n{}",
-            self.announce,
-            self.info.length,
+            self.announce.as_deref().unwrap_or_default(),
+            self.info.length(),
             self.info.get_hex_hash(),
             self.info.piece_length,
             self.info.get_piece_hashes().join("\n")
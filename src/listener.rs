@@ -0,0 +1,80 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+use crate::handshake::{self, PeerConnection, Timeouts};
+
+/// Port we listen on and announce to trackers
+pub const PORT: u16 = 6881;
+
+/// A peer that connected to us, with its address and the finished handshake
+pub type InboundPeer = (String, PeerConnection);
+
+/// Inbound connections waiting for the torrent's download to pick them up
+const BACKLOG: usize = 16;
+
+/// Accepts peers connecting to us and hands each one to the torrent it asks for
+pub struct Listener {
+    torrents: Mutex<HashMap<[u8; 20], mpsc::Sender<InboundPeer>>>,
+    timeouts: Timeouts,
+}
+
+impl Listener {
+    /// Starts accepting connections on `port` in the background
+    pub async fn listen(port: u16, timeouts: Timeouts) -> Result<Arc<Self>, io::Error> {
+        let socket = TcpListener::bind(("0.0.0.0", port)).await?;
+        let listener = Arc::new(Self {
+            torrents: Mutex::default(),
+            timeouts,
+        });
+
+        let accepting = listener.clone();
+        tokio::spawn(async move {
+            loop {
+                match socket.accept().await {
+                    Ok((stream, address)) => {
+                        let listener = accepting.clone();
+                        tokio::spawn(async move {
+                            listener.hand_over(stream, address.to_string()).await
+                        });
+                    }
+                    Err(e) => eprintln!("Failed to accept a peer: {}", e),
+                }
+            }
+        });
+        Ok(listener)
+    }
+
+    /// Peers asking for `info_hash` from now on, until `unregister`
+    pub fn register(&self, info_hash: [u8; 20]) -> mpsc::Receiver<InboundPeer> {
+        let (sender, receiver) = mpsc::channel(BACKLOG);
+        self.torrents.lock().unwrap().insert(info_hash, sender);
+        receiver
+    }
+
+    pub fn unregister(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+
+    async fn hand_over(&self, stream: TcpStream, peer: String) {
+        let accepted = handshake::accept(stream, &self.timeouts, |info_hash| {
+            self.torrents.lock().unwrap().contains_key(info_hash)
+        })
+        .await;
+        let (connection, info_hash) = match accepted {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                eprintln!("Rejecting peer {}: {}", peer, e);
+                return;
+            }
+        };
+
+        //? The torrent may have finished while the handshake was going on
+        let sender = self.torrents.lock().unwrap().get(&info_hash).cloned();
+        if let Some(sender) = sender {
+            let _ = sender.send((peer, connection)).await;
+        }
+    }
+}
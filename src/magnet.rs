@@ -0,0 +1,247 @@
+use serde_bencode::{from_bytes, to_bytes};
+use sha1::{Digest, Sha1};
+use std::io;
+use std::str::FromStr;
+use thiserror::Error;
+use tokio::task::JoinSet;
+
+use crate::extension::{self, MetadataMessage};
+use crate::handshake::{HandshakeError, Timeouts};
+use crate::info::{Info, Metadata};
+use crate::message::PeerMessage;
+use crate::peers::{self, TrackerError};
+use crate::{decode, download_piece, handshake, random};
+
+/// Refuse info dictionaries larger than this, a peer could otherwise make us allocate anything
+const MAX_METADATA_SIZE: usize = 16 * 1_024 * 1_024;
+
+#[derive(Debug, Error)]
+pub enum MagnetError {
+    #[error("Not a magnet link: {0}")]
+    InvalidUri(String),
+    #[error("Magnet link has no BitTorrent info-hash")]
+    MissingInfoHash,
+    #[error("Invalid info-hash: {0}")]
+    InvalidInfoHash(String),
+    #[error(transparent)]
+    Tracker(#[from] TrackerError),
+    #[error(transparent)]
+    Handshake(#[from] HandshakeError),
+    #[error("Peer I/O failed: {0}")]
+    Io(#[from] io::Error),
+    #[error("Invalid metadata message: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Peer does not support metadata exchange")]
+    Unsupported,
+    #[error("Peer rejected the metadata request")]
+    Rejected,
+    #[error("Peer sent metadata of an invalid size")]
+    InvalidSize,
+    #[error("Metadata does not match the info-hash")]
+    HashMismatch,
+    #[error("No peer could provide the metadata")]
+    NoPeerHadMetadata,
+}
+
+#[derive(Debug, Clone)]
+pub struct Magnet {
+    pub info_hash: [u8; 20],
+    pub display_name: Option<String>,
+    pub trackers: Vec<String>,
+    /// Peers given directly in the link (`x.pe`)
+    pub peers: Vec<String>,
+}
+
+impl FromStr for Magnet {
+    type Err = MagnetError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let query = uri
+            .strip_prefix("magnet:?")
+            .ok_or_else(|| MagnetError::InvalidUri(uri.to_string()))?;
+        let parameters: Vec<(String, String)> = serde_urlencoded::from_str(query)
+            .map_err(|_| MagnetError::InvalidUri(uri.to_string()))?;
+
+        let mut info_hash = None;
+        let mut display_name = None;
+        let mut trackers = Vec::new();
+        let mut peers = Vec::new();
+
+        for (key, value) in parameters {
+            match key.as_str() {
+                "xt" => {
+                    if let Some(hash) = value.strip_prefix("urn:btih:") {
+                        info_hash = Some(decode_info_hash(hash)?);
+                    }
+                }
+                "dn" => display_name = Some(value),
+                "tr" => trackers.push(value),
+                "x.pe" => peers.push(value),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            info_hash: info_hash.ok_or(MagnetError::MissingInfoHash)?,
+            display_name,
+            trackers,
+            peers,
+        })
+    }
+}
+
+//? The info-hash is either 40 hex characters or 32 base32 characters
+fn decode_info_hash(hash: &str) -> Result<[u8; 20], MagnetError> {
+    let invalid = || MagnetError::InvalidInfoHash(hash.to_string());
+
+    let bytes = match hash.len() {
+        40 => hex::decode(hash).map_err(|_| invalid())?,
+        32 => {
+            let mut bytes = Vec::with_capacity(20);
+            let mut buffer: u64 = 0;
+            let mut bits = 0;
+            for character in hash.to_ascii_uppercase().bytes() {
+                let value = match character {
+                    b'A'..=b'Z' => character - b'A',
+                    b'2'..=b'7' => character - b'2' + 26,
+                    _ => return Err(invalid()),
+                };
+                buffer = (buffer << 5) | value as u64;
+                bits += 5;
+                if bits >= 8 {
+                    bits -= 8;
+                    bytes.push((buffer >> bits) as u8);
+                }
+            }
+            bytes
+        }
+        _ => return Err(invalid()),
+    };
+
+    bytes.try_into().map_err(|_| invalid())
+}
+
+/// Finds peers for the magnet link and fetches the info dictionary from them (BEP 9)
+pub async fn get_metadata(magnet: &Magnet, timeouts: &Timeouts) -> Result<Metadata, MagnetError> {
+    let mut tiers = vec![magnet.trackers.clone()];
+    tiers.retain(|tier| !tier.is_empty());
+    for tier in tiers.iter_mut() {
+        random::shuffle(tier);
+    }
+
+    let mut peers = magnet.peers.clone();
+    if !tiers.is_empty() {
+        //? We do not know the size yet, anything but 0 so we do not look like a seed
+        match peers::announce_to_tiers(&mut tiers, &magnet.info_hash, 1).await {
+            Ok(tracker_peers) => peers.extend(tracker_peers),
+            Err(e) if peers.is_empty() => return Err(e.into()),
+            Err(e) => eprintln!("Failed to get peers: {}", e),
+        }
+    }
+
+    //? Ask every peer at once, the first verified info dictionary wins
+    let mut fetches = JoinSet::new();
+    for peer in peers {
+        let info_hash = magnet.info_hash;
+        let timeouts = *timeouts;
+        fetches.spawn(async move {
+            fetch_info(&info_hash, &peer, &timeouts)
+                .await
+                .map_err(|e| (peer, e))
+        });
+    }
+
+    while let Some(fetch) = fetches.join_next().await {
+        match fetch {
+            Ok(Ok(info)) => {
+                return Ok(Metadata {
+                    announce: tiers.first().and_then(|tier| tier.first()).cloned(),
+                    announce_list: tiers,
+                    info,
+                })
+            }
+            Ok(Err((peer, e))) => eprintln!("Peer {} failed: {}", peer, e),
+            Err(e) => eprintln!("Metadata fetch panicked: {}", e),
+        }
+    }
+
+    Err(MagnetError::NoPeerHadMetadata)
+}
+
+async fn fetch_info(
+    info_hash: &[u8; 20],
+    peer: &str,
+    timeouts: &Timeouts,
+) -> Result<Info, MagnetError> {
+    let connection = handshake::handshake(info_hash, peer, timeouts).await?;
+    if !connection.supports_extension_protocol() {
+        return Err(MagnetError::Unsupported);
+    }
+
+    let ut_metadata = connection
+        .extension_id("ut_metadata")
+        .ok_or(MagnetError::Unsupported)?;
+    let metadata_size = connection
+        .metadata_size()
+        .filter(|&size| size > 0 && size <= MAX_METADATA_SIZE)
+        .ok_or(MagnetError::InvalidSize)?;
+    let pieces_count = metadata_size.div_ceil(extension::METADATA_PIECE_SIZE);
+
+    for piece in 0..pieces_count {
+        let request = to_bytes(&MetadataMessage {
+            msg_type: extension::METADATA_REQUEST,
+            piece: piece as i64,
+            total_size: None,
+        })?;
+        download_piece::send_message(
+            &connection,
+            &PeerMessage::Extended {
+                id: ut_metadata,
+                payload: request,
+            },
+        )
+        .await?;
+    }
+
+    let mut raw = vec![0; metadata_size];
+    let mut received = vec![false; pieces_count];
+    while received.contains(&false) {
+        let message = download_piece::receive_message_within(&connection, timeouts.request);
+        let payload = match message.await? {
+            PeerMessage::Extended { id, payload } if id == extension::UT_METADATA_ID => payload,
+            _ => continue,
+        };
+
+        //? A data message is a bencoded dictionary directly followed by the piece bytes
+        let dictionary_end = decode::value_end(&payload, 0).ok_or(MagnetError::InvalidSize)?;
+        let header = from_bytes::<MetadataMessage>(&payload[..dictionary_end])?;
+
+        match header.msg_type {
+            extension::METADATA_DATA => {
+                let piece = usize::try_from(header.piece)
+                    .ok()
+                    .filter(|&piece| piece < pieces_count)
+                    .ok_or(MagnetError::InvalidSize)?;
+                let start = piece * extension::METADATA_PIECE_SIZE;
+                let end = (start + extension::METADATA_PIECE_SIZE).min(metadata_size);
+                let data = &payload[dictionary_end..];
+                if data.len() != end - start {
+                    return Err(MagnetError::InvalidSize);
+                }
+
+                raw[start..end].copy_from_slice(data);
+                received[piece] = true;
+            }
+            extension::METADATA_REJECT => return Err(MagnetError::Rejected),
+            _ => {}
+        }
+    }
+
+    //? Only trust the metadata if it hashes to what the magnet link promised
+    let hash: [u8; 20] = Sha1::digest(&raw).into();
+    if hash != *info_hash {
+        return Err(MagnetError::HashMismatch);
+    }
+
+    Ok(Info::from_raw(raw)?)
+}
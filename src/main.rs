@@ -1,22 +1,39 @@
 use clap::Parser;
 use serde_bencode::from_bytes;
+use std::time::Duration;
 use tokio::sync::RwLock;
 
+mod choker;
 mod cli;
 mod decode;
 mod download;
 mod download_piece;
+mod extension;
 mod handshake;
 mod info;
+mod listener;
+mod magnet;
+mod message;
 mod peers;
+mod picker;
+mod pipeline;
+mod random;
+mod rate_limit;
+mod resume;
+mod storage;
+mod udp_tracker;
+mod upload;
+mod verify;
 
 use cli::Cli;
 use decode::BencodeValue;
 use download::download;
 use download_piece::download_piece;
-use handshake::get_handshake;
-use info::get_info;
+use handshake::{get_handshake, Timeouts};
+use info::{get_info, get_metadata};
 use peers::get_peers;
+use rate_limit::RateLimits;
+use verify::verify;
 
 #[tokio::main]
 async fn main() {
@@ -29,17 +46,29 @@ async fn main() {
                 .expect("Invalid bencoded value")
                 .to_json()
         ),
-        Some(cli::Commands::Info { torrent_file }) => println!("{}", get_info(&torrent_file)),
+        Some(cli::Commands::Info { torrent_file }) => {
+            println!(
+                "{}",
+                get_metadata(&torrent_file, &Timeouts::default()).await
+            )
+        }
         Some(cli::Commands::Peers { torrent_file }) => {
             println!("{}", {
-                let metadata = RwLock::new(info::get_info(&torrent_file));
-                get_peers(&metadata).await.join("\n")
+                let metadata = RwLock::new(get_metadata(&torrent_file, &Timeouts::default()).await);
+                get_peers(&metadata)
+                    .await
+                    .expect("Failed to get peers")
+                    .join("\n")
             })
         }
         Some(cli::Commands::Handshake { torrent_file, peer }) => {
             println!("Peer ID: {}", {
-                let metadata = RwLock::new(info::get_info(&torrent_file));
-                get_handshake(&metadata, &peer).await.0
+                let timeouts = Timeouts::default();
+                let metadata = RwLock::new(get_metadata(&torrent_file, &timeouts).await);
+                get_handshake(&metadata, &peer, &timeouts)
+                    .await
+                    .expect("Failed to handshake with peer")
+                    .peer_id_hex()
             })
         }
         Some(cli::Commands::DownloadPiece {
@@ -47,7 +76,9 @@ async fn main() {
             piece_index,
             output_path,
         }) => {
-            download_piece(&torrent_file, piece_index, &output_path)
+            let timeouts = Timeouts::default();
+            let metadata = get_metadata(&torrent_file, &timeouts).await;
+            download_piece(metadata, piece_index, &output_path, timeouts)
                 .await
                 .expect("Failed to download piece");
             println!(
@@ -59,16 +90,47 @@ async fn main() {
         Some(cli::Commands::Download {
             torrent_file,
             output_path,
+            seed,
+            download_limit,
+            upload_limit,
+            torrent_download_limit,
+            torrent_upload_limit,
+            connect_timeout,
+            handshake_timeout,
+            request_timeout,
         }) => {
-            download(&torrent_file, &output_path)
-                .await
-                .expect("Failed to download");
+            //? Past what a u64 holds in bytes is as good as no limit
+            let kib = |limit: Option<u64>| limit.map(|limit| limit.saturating_mul(1_024));
+            let global = RateLimits::new(kib(download_limit), kib(upload_limit));
+            let torrent = RateLimits::new(kib(torrent_download_limit), kib(torrent_upload_limit));
+            let timeouts = Timeouts {
+                connect: Duration::from_secs(connect_timeout),
+                handshake: Duration::from_secs(handshake_timeout),
+                request: Duration::from_secs(request_timeout),
+            };
+            download(
+                get_metadata(&torrent_file, &timeouts).await,
+                &output_path,
+                seed,
+                global.and(&torrent),
+                timeouts,
+            )
+            .await
+            .expect("Failed to download");
             println!(
                 "Downloaded {} to {}.",
-                torrent_file.to_str().unwrap(),
+                torrent_file,
                 output_path.to_str().unwrap()
             );
         }
+        Some(cli::Commands::Verify { torrent_file, path }) => {
+            let complete = verify(&get_info(&torrent_file), &path)
+                .await
+                .expect("Failed to verify");
+            if !complete {
+                std::process::exit(1);
+            }
+        }
         None => rdza::rdza! {kurwa!("Podaj jakąś komendę debulu...")},
     }
 }
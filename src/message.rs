@@ -0,0 +1,296 @@
+use bytes::BytesMut;
+use std::fmt::Display;
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Largest message we accept from a peer, enough for a 16 KiB block or the bitfield
+/// of a torrent with millions of pieces, while stopping a peer from making us allocate anything
+pub const MAX_MESSAGE_LENGTH: u32 = 2 * 1_024 * 1_024;
+
+const CHOKE: u8 = 0;
+const UNCHOKE: u8 = 1;
+const INTERESTED: u8 = 2;
+const NOT_INTERESTED: u8 = 3;
+const HAVE: u8 = 4;
+const BITFIELD: u8 = 5;
+const REQUEST: u8 = 6;
+const PIECE: u8 = 7;
+const CANCEL: u8 = 8;
+const PORT: u8 = 9;
+const EXTENDED: u8 = 20;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PeerMessage {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have {
+        piece_index: u32,
+    },
+    Bitfield(Vec<u8>),
+    Request {
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Piece {
+        piece_index: u32,
+        begin: u32,
+        block: Vec<u8>,
+    },
+    Cancel {
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    },
+    Port(u16),
+    /// Extension protocol message (BEP 10), `id` is the extended message ID
+    Extended {
+        id: u8,
+        payload: Vec<u8>,
+    },
+    /// Messages of extensions we never advertised, kept so they can be skipped
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+impl PeerMessage {
+    /// Encodes the message with its 4 byte length prefix, ready to be written to the stream
+    pub fn encode(&self) -> Vec<u8> {
+        let mut body = Vec::new();
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => body.push(CHOKE),
+            PeerMessage::Unchoke => body.push(UNCHOKE),
+            PeerMessage::Interested => body.push(INTERESTED),
+            PeerMessage::NotInterested => body.push(NOT_INTERESTED),
+            PeerMessage::Have { piece_index } => {
+                body.push(HAVE);
+                body.extend(piece_index.to_be_bytes());
+            }
+            PeerMessage::Bitfield(bitfield) => {
+                body.push(BITFIELD);
+                body.extend(bitfield);
+            }
+            PeerMessage::Request {
+                piece_index,
+                begin,
+                length,
+            } => {
+                body.push(REQUEST);
+                body.extend(piece_index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            }
+            PeerMessage::Piece {
+                piece_index,
+                begin,
+                block,
+            } => {
+                body.push(PIECE);
+                body.extend(piece_index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(block);
+            }
+            PeerMessage::Cancel {
+                piece_index,
+                begin,
+                length,
+            } => {
+                body.push(CANCEL);
+                body.extend(piece_index.to_be_bytes());
+                body.extend(begin.to_be_bytes());
+                body.extend(length.to_be_bytes());
+            }
+            PeerMessage::Port(port) => {
+                body.push(PORT);
+                body.extend(port.to_be_bytes());
+            }
+            PeerMessage::Extended { id, payload } => {
+                body.push(EXTENDED);
+                body.push(*id);
+                body.extend(payload);
+            }
+            PeerMessage::Unknown { id, payload } => {
+                body.push(*id);
+                body.extend(payload);
+            }
+        }
+
+        let mut message = Vec::with_capacity(4 + body.len());
+        message.extend((body.len() as u32).to_be_bytes());
+        message.extend(body);
+        message
+    }
+
+    /// Decodes a message from its body, i.e. everything after the length prefix
+    pub fn decode(body: &[u8]) -> Result<Self, io::Error> {
+        let Some((&id, payload)) = body.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Invalid payload of {} bytes for message {}",
+                    payload.len(),
+                    id
+                ),
+            )
+        };
+        let expect_length = |length: usize| {
+            if payload.len() == length {
+                Ok(())
+            } else {
+                Err(invalid())
+            }
+        };
+
+        let message = match id {
+            CHOKE => expect_length(0).map(|_| PeerMessage::Choke)?,
+            UNCHOKE => expect_length(0).map(|_| PeerMessage::Unchoke)?,
+            INTERESTED => expect_length(0).map(|_| PeerMessage::Interested)?,
+            NOT_INTERESTED => expect_length(0).map(|_| PeerMessage::NotInterested)?,
+            HAVE => {
+                expect_length(4)?;
+                PeerMessage::Have {
+                    piece_index: read_u32(&payload[0..4]),
+                }
+            }
+            BITFIELD => PeerMessage::Bitfield(payload.to_vec()),
+            REQUEST | CANCEL => {
+                expect_length(12)?;
+                let (piece_index, begin, length) = (
+                    read_u32(&payload[0..4]),
+                    read_u32(&payload[4..8]),
+                    read_u32(&payload[8..12]),
+                );
+                if id == REQUEST {
+                    PeerMessage::Request {
+                        piece_index,
+                        begin,
+                        length,
+                    }
+                } else {
+                    PeerMessage::Cancel {
+                        piece_index,
+                        begin,
+                        length,
+                    }
+                }
+            }
+            PIECE => {
+                if payload.len() < 8 {
+                    return Err(invalid());
+                }
+                PeerMessage::Piece {
+                    piece_index: read_u32(&payload[0..4]),
+                    begin: read_u32(&payload[4..8]),
+                    block: payload[8..].to_vec(),
+                }
+            }
+            PORT => {
+                expect_length(2)?;
+                PeerMessage::Port(u16::from_be_bytes([payload[0], payload[1]]))
+            }
+            EXTENDED => {
+                let (&extended_id, extended_payload) = payload.split_first().ok_or_else(invalid)?;
+                PeerMessage::Extended {
+                    id: extended_id,
+                    payload: extended_payload.to_vec(),
+                }
+            }
+            _ => PeerMessage::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
+        };
+
+        Ok(message)
+    }
+
+    /// Reads one length-prefixed message, refusing anything over `MAX_MESSAGE_LENGTH`
+    pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<Self, io::Error> {
+        let length = reader.read_u32().await?;
+        check_length(length)?;
+
+        let mut body = vec![0; length as usize];
+        reader.read_exact(&mut body).await?;
+        Self::decode(&body)
+    }
+
+    /// Takes the first message off the front of `buffer`, `None` until all of it arrived
+    pub fn parse(buffer: &mut BytesMut) -> Result<Option<Self>, io::Error> {
+        let Some(prefix) = buffer.get(..4) else {
+            return Ok(None);
+        };
+        let length = read_u32(prefix);
+        check_length(length)?;
+
+        if buffer.len() < 4 + length as usize {
+            return Ok(None);
+        }
+        let frame = buffer.split_to(4 + length as usize);
+        Self::decode(&frame[4..]).map(Some)
+    }
+}
+
+impl Display for PeerMessage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerMessage::Bitfield(bitfield) => write!(f, "Bitfield({} bytes)", bitfield.len()),
+            PeerMessage::Piece {
+                piece_index,
+                begin,
+                block,
+            } => write!(
+                f,
+                "Piece {{ piece_index: {}, begin: {}, block: {} bytes }}",
+                piece_index,
+                begin,
+                block.len()
+            ),
+            message => write!(f, "{:?}", message),
+        }
+    }
+}
+
+fn check_length(length: u32) -> Result<(), io::Error> {
+    if length > MAX_MESSAGE_LENGTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Message of {} bytes exceeds the maximum length", length),
+        ));
+    }
+    Ok(())
+}
+
+/// Packs one bool per piece into bytes, high bit first, as in the bitfield message
+pub fn encode_bitfield(pieces: &[bool]) -> Vec<u8> {
+    pieces
+        .chunks(8)
+        .map(|chunk| {
+            chunk
+                .iter()
+                .enumerate()
+                .fold(0, |byte, (i, &has)| byte | ((has as u8) << (7 - i)))
+        })
+        .collect()
+}
+
+/// Unpacks a bitfield, the result is padded to a multiple of 8 pieces
+pub fn decode_bitfield(bitfield: &[u8]) -> Vec<bool> {
+    bitfield
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .collect()
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
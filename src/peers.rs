@@ -1,42 +1,153 @@
 use crate::info::Metadata;
+use crate::listener;
+use crate::udp_tracker::{self, Backoff};
 use serde::{self, Deserialize, Serialize};
 use serde_bencode::from_bytes;
 use serde_bytes::ByteBuf;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+use std::time::Duration;
+use thiserror::Error;
 use tokio::sync::RwLock;
 
-pub async fn get_peers(metadata: &RwLock<Metadata>) -> Vec<String> {
-    let metadata = metadata.read().await;
+//? A tracker that does not answer in time is skipped for the next one of the tier
+const HTTP_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+    #[error("HTTP request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("Invalid tracker response: {0}")]
+    Bencode(#[from] serde_bencode::Error),
+    #[error("Tracker I/O failed: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid tracker response: {0}")]
+    InvalidResponse(&'static str),
+    #[error("Invalid tracker URL: {0}")]
+    InvalidUrl(String),
+    #[error("Tracker did not answer in time")]
+    Timeout,
+    #[error("Tracker refused the announce: {0}")]
+    Failure(String),
+    #[error("No tracker answered")]
+    NoTrackerAnswered,
+}
+
+pub async fn get_peers(metadata: &RwLock<Metadata>) -> Result<Vec<String>, TrackerError> {
+    let (mut tiers, info_hash, left) = {
+        let metadata = metadata.read().await;
+        (
+            metadata.announce_list.clone(),
+            metadata.info.get_hash(),
+            metadata.info.length(),
+        )
+    };
+
+    let peers = announce_to_tiers(&mut tiers, &info_hash, left).await;
+
+    //? Remember which trackers answered for the next announce
+    metadata.write().await.announce_list = tiers;
+    peers
+}
+
+/// BEP 12: goes tier by tier and returns the peers of the first tracker to answer,
+/// moving that tracker to the front of its tier. Silent trackers are given up on quickly,
+/// only the last one left gets the full back-off
+pub async fn announce_to_tiers(
+    tiers: &mut [Vec<String>],
+    info_hash: &[u8; 20],
+    left: u64,
+) -> Result<Vec<String>, TrackerError> {
+    let mut remaining: usize = tiers.iter().map(Vec::len).sum();
+    for tier in tiers.iter_mut() {
+        for tracker_index in 0..tier.len() {
+            remaining -= 1;
+            let backoff = match remaining {
+                0 => Backoff::PATIENT,
+                _ => Backoff::HASTY,
+            };
+            match announce(&tier[tracker_index], info_hash, left, backoff).await {
+                Ok(peers) => {
+                    let tracker = tier.remove(tracker_index);
+                    tier.insert(0, tracker);
+                    return Ok(peers);
+                }
+                Err(e) => eprintln!("Tracker {} failed: {}", tier[tracker_index], e),
+            }
+        }
+    }
+
+    Err(TrackerError::NoTrackerAnswered)
+}
+
+async fn announce(
+    tracker: &str,
+    info_hash: &[u8; 20],
+    left: u64,
+    backoff: Backoff,
+) -> Result<Vec<String>, TrackerError> {
+    if tracker.starts_with("udp://") {
+        let request = udp_tracker::AnnounceRequest {
+            info_hash: *info_hash,
+            peer_id: *b"21372137696921372137",
+            downloaded: 0,
+            left,
+            uploaded: 0,
+            port: listener::PORT,
+        };
+        return udp_tracker::announce(tracker, &request, backoff).await;
+    }
+
     let dicover_peers_query = DiscoverPeersQuery::new(
         "21372137696921372137".to_string(),
-        6881,
+        listener::PORT.into(),
         0,
         0,
-        metadata.info.length,
+        left,
         1,
-        metadata.info.get_hash(),
+        *info_hash,
     );
 
-    let res = reqwest::get(format!(
-        "{}?{}",
-        metadata.announce,
-        dicover_peers_query.get_query_string()
-    ))
-    .await
-    .unwrap();
+    let client = reqwest::Client::builder()
+        .connect_timeout(HTTP_CONNECT_TIMEOUT)
+        .timeout(HTTP_TIMEOUT)
+        .build()?;
+    let separator = if tracker.contains('?') { '&' } else { '?' };
+    let res = client
+        .get(format!(
+            "{}{}{}",
+            tracker,
+            separator,
+            dicover_peers_query.get_query_string()
+        ))
+        .send()
+        .await
+        .map_err(http_error)?;
+
+    let bytes = res.bytes().await.map_err(http_error)?;
+    let decoded = from_bytes::<DiscoverPeersResponse>(&bytes)?;
 
-    let bytes = res.bytes().await.unwrap();
-    let decoded = from_bytes::<DiscoverPeersResponse>(&bytes).unwrap();
+    if let Some(reason) = decoded.failure_reason {
+        return Err(TrackerError::Failure(reason));
+    }
 
-    decoded.get_peers()
+    Ok(decoded.get_peers())
+}
+
+fn http_error(e: reqwest::Error) -> TrackerError {
+    match e.is_timeout() {
+        true => TrackerError::Timeout,
+        false => TrackerError::Http(e),
+    }
 }
 
 #[derive(Serialize, Deserialize)]
 struct DiscoverPeersQuery {
     peer_id: String,
     port: u32,
-    uploaded: u32,
-    downloaded: u32,
-    left: u32,
+    uploaded: u64,
+    downloaded: u64,
+    left: u64,
     compact: u32,
     info_hash: String,
 }
@@ -45,9 +156,9 @@ impl DiscoverPeersQuery {
     pub fn new(
         peer_id: String,
         port: u32,
-        uploaded: u32,
-        downloaded: u32,
-        left: u32,
+        uploaded: u64,
+        downloaded: u64,
+        left: u64,
         compact: u32,
         info_hash: [u8; 20],
     ) -> Self {
@@ -85,34 +196,83 @@ fn urlencode(t: &[u8; 20]) -> String {
 
 #[derive(Serialize, Deserialize, Debug)]
 struct DiscoverPeersResponse {
-    complete: u32,
-    incomplete: u32,
-    interval: u32,
+    #[serde(rename = "failure reason")]
+    failure_reason: Option<String>,
+    complete: Option<u32>,
+    incomplete: Option<u32>,
+    interval: Option<u32>,
     #[serde(rename = "min interval")]
-    min_interval: u32,
-    peers: ByteBuf,
+    min_interval: Option<u32>,
+    #[serde(default)]
+    peers: PeerList,
+    peers6: Option<ByteBuf>,
+}
+
+//? Trackers answer either with the compact string or, for older ones, a list of dictionaries
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(untagged)]
+enum PeerList {
+    Compact(ByteBuf),
+    Dictionaries(Vec<PeerDictionary>),
+}
+
+impl Default for PeerList {
+    fn default() -> Self {
+        PeerList::Compact(ByteBuf::new())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PeerDictionary {
+    ip: String,
+    port: u16,
 }
 
 impl DiscoverPeersResponse {
     pub fn get_peers(&self) -> Vec<String> {
-        let mut peers: Vec<String> = Vec::new();
-        let mut curr_peer: Vec<u8> = Vec::new();
-
-        for (i, byte) in self.peers.iter().enumerate() {
-            curr_peer.push(byte.to_owned());
-            if (i + 1) % 6 == 0 {
-                peers.push(format!(
-                    "{}.{}.{}.{}:{}",
-                    curr_peer[0],
-                    curr_peer[1],
-                    curr_peer[2],
-                    curr_peer[3],
-                    (((curr_peer[4] as u16) << 8) + curr_peer[5] as u16)
-                ));
-                curr_peer = Vec::new();
-            }
+        let mut peers: Vec<String> = match &self.peers {
+            PeerList::Compact(bytes) => parse_compact_peers_v4(bytes),
+            PeerList::Dictionaries(dictionaries) => dictionaries
+                .iter()
+                .map(|peer| match peer.ip.parse::<Ipv6Addr>() {
+                    Ok(ip) => SocketAddrV6::new(ip, peer.port, 0, 0).to_string(),
+                    Err(_) => format!("{}:{}", peer.ip, peer.port),
+                })
+                .collect(),
+        };
+
+        if let Some(peers6) = &self.peers6 {
+            peers.extend(parse_compact_peers_v6(peers6));
         }
 
+        //? Merge without duplicates, keeping the order the tracker gave
+        let mut seen = std::collections::HashSet::new();
+        peers.retain(|peer| seen.insert(peer.clone()));
+
         peers
     }
 }
+
+/// Compact IPv4 peers: 4 bytes of address followed by a 2 byte port, all big-endian
+pub fn parse_compact_peers_v4(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks_exact(6)
+        .map(|peer| {
+            let ip = Ipv4Addr::new(peer[0], peer[1], peer[2], peer[3]);
+            let port = u16::from_be_bytes([peer[4], peer[5]]);
+            SocketAddrV4::new(ip, port).to_string()
+        })
+        .collect()
+}
+
+/// Compact IPv6 peers: 16 bytes of address followed by a 2 byte port, all big-endian
+pub fn parse_compact_peers_v6(bytes: &[u8]) -> Vec<String> {
+    bytes
+        .chunks_exact(18)
+        .map(|peer| {
+            let ip: [u8; 16] = peer[..16].try_into().unwrap();
+            let port = u16::from_be_bytes([peer[16], peer[17]]);
+            SocketAddrV6::new(Ipv6Addr::from(ip), port, 0, 0).to_string()
+        })
+        .collect()
+}
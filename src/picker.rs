@@ -0,0 +1,188 @@
+use std::sync::Mutex;
+use tokio::sync::Notify;
+
+use crate::random;
+
+/// Decides which piece to download next, rarest first
+#[derive(Debug)]
+pub struct PiecePicker {
+    /// Number of known peers that have each piece
+    availability: Vec<u32>,
+    /// Number of peers currently downloading each piece, more than one only in endgame
+    downloaders: Vec<u32>,
+    verified: Vec<bool>,
+}
+
+impl PiecePicker {
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            availability: vec![0; pieces_count],
+            downloaders: vec![0; pieces_count],
+            verified: vec![false; pieces_count],
+        }
+    }
+
+    /// Counts every piece in a peer's bitfield, including the haves it sent so far;
+    /// extra bits past the last piece are ignored
+    pub fn add_bitfield(&mut self, bitfield: &[bool]) {
+        for (count, &has) in self.availability.iter_mut().zip(bitfield) {
+            if has {
+                *count += 1;
+            }
+        }
+    }
+
+    /// Forgets a peer's pieces, when it disconnects or before counting its newer bitfield
+    pub fn remove_bitfield(&mut self, bitfield: &[bool]) {
+        for (count, &has) in self.availability.iter_mut().zip(bitfield) {
+            if has {
+                *count = count.saturating_sub(1);
+            }
+        }
+    }
+
+    /// Hands out the rarest piece the peer has that nobody got yet, ties are broken randomly.
+    /// In endgame, when every missing piece is being downloaded, it hands out the piece the
+    /// fewest peers are working on instead, so the last pieces do not wait on one slow peer;
+    /// only to `idle` peers, one still busy with other pieces has no use for a duplicate yet.
+    /// Pieces in `avoid` are left to other peers, unless nobody else has them
+    pub fn pick(&mut self, bitfield: &[bool], avoid: &[bool], idle: bool) -> Option<usize> {
+        let missing = (0..self.verified.len()).filter(|&index| !self.verified[index]);
+        let endgame = idle && missing.clone().all(|index| self.downloaders[index] > 0);
+
+        let candidates = missing.filter(|&index| {
+            bitfield.get(index) == Some(&true)
+                && (endgame || self.downloaders[index] == 0)
+                && (avoid.get(index) != Some(&true) || self.availability[index] <= 1)
+        });
+        let rank = |index: usize| (self.downloaders[index], self.availability[index]);
+        let best = candidates.clone().map(rank).min()?;
+        let best: Vec<usize> = candidates.filter(|&index| rank(index) == best).collect();
+
+        let piece_index = best[random::below(best.len())];
+        self.downloaders[piece_index] += 1;
+        Some(piece_index)
+    }
+
+    /// A peer stopped working on the piece, if nobody else is another peer can pick it
+    pub fn release(&mut self, piece_index: usize) {
+        self.downloaders[piece_index] = self.downloaders[piece_index].saturating_sub(1);
+    }
+
+    pub fn complete(&mut self, piece_index: usize) {
+        self.release(piece_index);
+        self.verified[piece_index] = true;
+    }
+
+    pub fn is_verified(&self, piece_index: usize) -> bool {
+        self.verified.get(piece_index).copied().unwrap_or(false)
+    }
+
+    pub fn verified(&self) -> Vec<bool> {
+        self.verified.clone()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.verified.iter().all(|&verified| verified)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.verified.iter().filter(|&&verified| !verified).count()
+    }
+}
+
+/// Pieces left to download, shared by all peers; idle peers pull the rarest piece they have
+#[derive(Debug)]
+pub struct WorkQueue {
+    picker: Mutex<PiecePicker>,
+    /// Woken whenever a piece is released or completed
+    changed: Notify,
+    /// Woken whenever a piece is completed
+    completed: Notify,
+}
+
+impl WorkQueue {
+    pub fn new(pieces_count: usize) -> Self {
+        Self {
+            picker: Mutex::new(PiecePicker::new(pieces_count)),
+            changed: Notify::new(),
+            completed: Notify::new(),
+        }
+    }
+
+    /// Replaces what we counted for a peer with its current bitfield
+    pub fn update_availability(&self, counted: &[bool], bitfield: &[bool]) {
+        //? Most rounds the peer has not announced anything new
+        if counted == bitfield {
+            return;
+        }
+        let mut picker = self.picker.lock().unwrap();
+        picker.remove_bitfield(counted);
+        picker.add_bitfield(bitfield);
+        //? A piece to avoid may have become the last copy around
+        self.changed.notify_waiters();
+    }
+
+    /// Waits for a piece the peer has, `None` once every piece is verified
+    pub async fn next(&self, bitfield: &[bool], avoid: &[bool], idle: bool) -> Option<usize> {
+        loop {
+            //? Register before checking, so a release in between is not missed
+            let changed = self.changed.notified();
+            {
+                let mut picker = self.picker.lock().unwrap();
+                if picker.is_done() {
+                    return None;
+                }
+                if let Some(piece_index) = picker.pick(bitfield, avoid, idle) {
+                    //? Handing out the last piece starts the endgame for the idle peers
+                    self.changed.notify_waiters();
+                    return Some(piece_index);
+                }
+            }
+            changed.await;
+        }
+    }
+
+    pub fn release(&self, piece_index: usize) {
+        self.picker.lock().unwrap().release(piece_index);
+        self.changed.notify_waiters();
+    }
+
+    pub fn complete(&self, piece_index: usize) {
+        self.picker.lock().unwrap().complete(piece_index);
+        self.changed.notify_waiters();
+        self.completed.notify_waiters();
+    }
+
+    /// Resolves on the next completed piece
+    pub async fn progressed(&self) {
+        self.completed.notified().await
+    }
+
+    /// Resolves once every piece is verified
+    pub async fn finished(&self) {
+        loop {
+            let changed = self.changed.notified();
+            if self.is_done() {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    pub fn is_verified(&self, piece_index: usize) -> bool {
+        self.picker.lock().unwrap().is_verified(piece_index)
+    }
+
+    pub fn verified(&self) -> Vec<bool> {
+        self.picker.lock().unwrap().verified()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.picker.lock().unwrap().is_done()
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.picker.lock().unwrap().remaining()
+    }
+}
@@ -0,0 +1,230 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::download_piece::{self, Block, BLOCK_SIZE};
+use crate::message::PeerMessage;
+
+/// Requests in flight until we have measured the peer
+const INITIAL_DEPTH: usize = 5;
+const MIN_DEPTH: usize = 2;
+/// Upper bound for peers that do not advertise `reqq`, the default of most clients
+const MAX_DEPTH: usize = 250;
+/// Requests wait at the peer for about this long on top of the round trip, so it always has
+/// the next one at hand and the pipeline grows until the peer's bandwidth runs out
+const QUEUE_TIME: Duration = Duration::from_secs(1);
+/// Throughput is measured over windows at least this long
+const THROUGHPUT_WINDOW: Duration = Duration::from_secs(1);
+/// Weight of the latest window in the smoothed throughput
+const THROUGHPUT_SMOOTHING: f64 = 0.3;
+
+/// Blocks requested from one peer, across as many pieces as it takes to keep it busy.
+/// The number of requests in flight follows the peer's throughput and latency
+#[derive(Debug, Default)]
+pub struct Pipeline {
+    pieces: Vec<PipelinedPiece>,
+    in_flight: Vec<Request>,
+    /// Bytes per second, smoothed over the measuring windows
+    throughput: Option<f64>,
+    /// Shortest time a request took to be answered, the round trip without queueing
+    latency: Option<Duration>,
+    /// Start of the current measuring window and the bytes received since, while busy
+    window: Option<(Instant, u64)>,
+}
+
+#[derive(Debug)]
+struct PipelinedPiece {
+    piece_index: u32,
+    unrequested: VecDeque<PeerMessage>,
+    blocks: Vec<Option<Block>>,
+    missing: usize,
+}
+
+#[derive(Debug)]
+struct Request {
+    piece_index: u32,
+    begin: u32,
+    length: u32,
+    sent: Instant,
+}
+
+impl Pipeline {
+    /// Queues up every block of the piece
+    pub fn add_piece(&mut self, piece_index: u32, piece_length: u64) -> Result<(), io::Error> {
+        let requests = download_piece::get_piece_blocks_messages(piece_index, piece_length)?;
+        self.pieces.push(PipelinedPiece {
+            piece_index,
+            blocks: vec![None; requests.len()],
+            missing: requests.len(),
+            unrequested: requests.into(),
+        });
+        Ok(())
+    }
+
+    /// Requests to keep in flight: enough to cover the round trip at the rate the peer sends,
+    /// plus the queueing time, never more than `reqq` if the peer told us
+    pub fn depth(&self, reqq: Option<usize>) -> usize {
+        let depth = match (self.throughput, self.latency) {
+            (Some(throughput), Some(latency)) => {
+                let seconds = (latency + QUEUE_TIME).as_secs_f64();
+                (throughput * seconds / BLOCK_SIZE as f64).ceil() as usize
+            }
+            _ => INITIAL_DEPTH,
+        };
+        depth.max(MIN_DEPTH).min(reqq.unwrap_or(MAX_DEPTH))
+    }
+
+    /// Whether the pieces we have run short of blocks to keep `depth` requests in flight
+    pub fn wants_piece(&self, depth: usize) -> bool {
+        let unrequested: usize = self
+            .pieces
+            .iter()
+            .map(|piece| piece.unrequested.len())
+            .sum();
+        self.in_flight.len() + unrequested < depth
+    }
+
+    /// Takes the next requests to send, topping the ones in flight up to `depth`
+    pub fn requests(&mut self, depth: usize) -> Vec<PeerMessage> {
+        let now = Instant::now();
+        let mut requests = Vec::new();
+        for piece in &mut self.pieces {
+            while self.in_flight.len() < depth {
+                let Some(request) = piece.unrequested.pop_front() else {
+                    break;
+                };
+                if let PeerMessage::Request {
+                    piece_index,
+                    begin,
+                    length,
+                } = request
+                {
+                    self.in_flight.push(Request {
+                        piece_index,
+                        begin,
+                        length,
+                        sent: now,
+                    });
+                }
+                requests.push(request);
+            }
+        }
+        if !self.in_flight.is_empty() && self.window.is_none() {
+            self.window = Some((now, 0));
+        }
+        requests
+    }
+
+    /// Files a block away, returns false if we did not ask for it or already got it
+    pub fn receive(&mut self, piece_index: u32, begin: u32, block: Vec<u8>) -> bool {
+        let Some(position) = self
+            .in_flight
+            .iter()
+            .position(|request| request.piece_index == piece_index && request.begin == begin)
+        else {
+            return false;
+        };
+        let request = self.in_flight.remove(position);
+        let Some(piece) = self
+            .pieces
+            .iter_mut()
+            .find(|piece| piece.piece_index == piece_index)
+        else {
+            return false;
+        };
+
+        let now = Instant::now();
+        let latency = now.duration_since(request.sent);
+        self.latency = Some(
+            self.latency
+                .map_or(latency, |shortest| shortest.min(latency)),
+        );
+        if let Some((start, bytes)) = &mut self.window {
+            *bytes += block.len() as u64;
+            let elapsed = now.duration_since(*start);
+            if elapsed >= THROUGHPUT_WINDOW {
+                let sample = *bytes as f64 / elapsed.as_secs_f64();
+                self.throughput = Some(self.throughput.map_or(sample, |throughput| {
+                    throughput + THROUGHPUT_SMOOTHING * (sample - throughput)
+                }));
+                *start = now;
+                *bytes = 0;
+            }
+        }
+        //? An idle peer says nothing about its throughput, measure again once busy
+        if self.in_flight.is_empty() {
+            self.window = None;
+        }
+
+        piece.blocks[(begin / BLOCK_SIZE) as usize] = Some(Block {
+            piece_index,
+            begin,
+            block,
+        });
+        piece.missing -= 1;
+        true
+    }
+
+    /// Takes out a piece that has all of its blocks
+    pub fn finished(&mut self) -> Option<(u32, Vec<Option<Block>>)> {
+        let position = self.pieces.iter().position(|piece| piece.missing == 0)?;
+        let piece = self.pieces.remove(position);
+        Some((piece.piece_index, piece.blocks))
+    }
+
+    /// When the oldest request in flight runs out of `timeout`
+    pub fn deadline(&self, timeout: Duration) -> Option<Instant> {
+        let oldest = self.in_flight.iter().map(|request| request.sent).min()?;
+        Some(oldest + timeout)
+    }
+
+    /// Pieces with a request that went unanswered for longer than `timeout`
+    pub fn timed_out(&self, timeout: Duration) -> Vec<u32> {
+        let mut pieces: Vec<u32> = self
+            .in_flight
+            .iter()
+            .filter(|request| request.sent.elapsed() >= timeout)
+            .map(|request| request.piece_index)
+            .collect();
+        pieces.sort_unstable();
+        pieces.dedup();
+        pieces
+    }
+
+    /// Drops a piece, returning cancels for the requests still in flight
+    pub fn abandon(&mut self, piece_index: u32) -> Vec<PeerMessage> {
+        self.pieces.retain(|piece| piece.piece_index != piece_index);
+
+        let mut cancels = Vec::new();
+        self.in_flight.retain(|request| {
+            if request.piece_index != piece_index {
+                return true;
+            }
+            cancels.push(PeerMessage::Cancel {
+                piece_index,
+                begin: request.begin,
+                length: request.length,
+            });
+            false
+        });
+        if self.in_flight.is_empty() {
+            self.window = None;
+        }
+        cancels
+    }
+
+    /// Drops everything, when the peer choked us and discarded our requests or is gone;
+    /// returns the pieces that were in progress
+    pub fn clear(&mut self) -> Vec<u32> {
+        self.in_flight.clear();
+        self.window = None;
+        self.pieces
+            .drain(..)
+            .map(|piece| piece.piece_index)
+            .collect()
+    }
+
+    pub fn pieces(&self) -> Vec<u32> {
+        self.pieces.iter().map(|piece| piece.piece_index).collect()
+    }
+}
@@ -0,0 +1,24 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+
+/// A random number, out of the randomly seeded keys std gives every `HashMap`.
+/// Good enough for shuffling and breaking ties, not for anything that has to be unpredictable
+pub fn u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+pub fn u32() -> u32 {
+    u64() as u32
+}
+
+/// A random index below `bound`, which must not be 0
+pub fn below(bound: usize) -> usize {
+    (u64() % bound as u64) as usize
+}
+
+/// Fisher-Yates shuffle
+pub fn shuffle<T>(items: &mut [T]) {
+    for index in (1..items.len()).rev() {
+        items.swap(index, below(index + 1));
+    }
+}
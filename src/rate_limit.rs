@@ -0,0 +1,99 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Lets through `rate` bytes per second on average, with bursts of up to a second's worth
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    /// Bytes that may go through right now, below zero when a transfer took more than was left
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(bytes_per_second: u64) -> Self {
+        Self {
+            rate: bytes_per_second as f64,
+            state: Mutex::new(BucketState {
+                tokens: bytes_per_second as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket and waits until the bucket has paid them back
+    pub async fn acquire(&self, bytes: usize) {
+        //? Go into debt rather than waiting for enough tokens, so a block larger than the
+        //? bucket still goes through, and whoever comes next waits for the debt to clear
+        let debt = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.refilled).as_secs_f64();
+            state.tokens = (state.tokens + elapsed * self.rate).min(self.rate);
+            state.refilled = now;
+            state.tokens -= bytes as f64;
+            -state.tokens
+        };
+        if debt > 0.0 {
+            tokio::time::sleep(Duration::from_secs_f64(debt / self.rate)).await;
+        }
+    }
+}
+
+/// Every bucket a transfer in one direction has to go through, the global one and the
+/// torrent's own; no buckets means no limit
+#[derive(Debug, Clone, Default)]
+pub struct RateLimit(Vec<Arc<TokenBucket>>);
+
+impl RateLimit {
+    /// Also limits to `bytes_per_second`, if set
+    pub fn with(mut self, bytes_per_second: Option<u64>) -> Self {
+        if let Some(bytes_per_second) = bytes_per_second.filter(|&rate| rate > 0) {
+            self.0.push(Arc::new(TokenBucket::new(bytes_per_second)));
+        }
+        self
+    }
+
+    /// Adds the buckets of another limit, for a torrent sharing the global limit
+    pub fn and(mut self, other: &RateLimit) -> Self {
+        self.0.extend(other.0.iter().cloned());
+        self
+    }
+
+    /// Waits until every bucket lets `bytes` through
+    pub async fn acquire(&self, bytes: usize) {
+        for bucket in &self.0 {
+            bucket.acquire(bytes).await;
+        }
+    }
+}
+
+/// Limits for both directions
+#[derive(Debug, Clone, Default)]
+pub struct RateLimits {
+    pub download: RateLimit,
+    pub upload: RateLimit,
+}
+
+impl RateLimits {
+    /// Limits in bytes per second, `None` for no limit
+    pub fn new(download: Option<u64>, upload: Option<u64>) -> Self {
+        Self {
+            download: RateLimit::default().with(download),
+            upload: RateLimit::default().with(upload),
+        }
+    }
+
+    /// Limits that apply on top of these ones
+    pub fn and(&self, other: &RateLimits) -> Self {
+        Self {
+            download: self.download.clone().and(&other.download),
+            upload: self.upload.clone().and(&other.upload),
+        }
+    }
+}
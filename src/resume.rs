@@ -0,0 +1,103 @@
+use serde::{Deserialize, Serialize};
+use serde_bencode::{from_bytes, to_bytes};
+use serde_bytes::ByteBuf;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+use crate::message;
+use crate::storage::Storage;
+
+/// What a previous run downloaded, only trusted while the files look exactly as it left them
+#[derive(Debug, Serialize, Deserialize)]
+struct ResumeState {
+    #[serde(rename = "info-hash")]
+    info_hash: ByteBuf,
+    /// Verified pieces, packed like the bitfield message
+    bitfield: ByteBuf,
+    files: Vec<FileStamp>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+struct FileStamp {
+    length: u64,
+    /// Modification time in nanoseconds since the Unix epoch
+    mtime: u64,
+}
+
+/// The fast-resume sidecar, kept next to the output as `<output>.resume`
+pub struct ResumeFile {
+    path: PathBuf,
+    info_hash: [u8; 20],
+    /// Saves from different peer tasks must not interleave
+    lock: Mutex<()>,
+}
+
+impl ResumeFile {
+    pub fn new(output_path: &Path, info_hash: [u8; 20]) -> Self {
+        let mut path = OsString::from(output_path);
+        path.push(".resume");
+
+        Self {
+            path: PathBuf::from(path),
+            info_hash,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Verified pieces from the last run, `None` if there is no usable record or the files
+    /// changed since it was written, in which case everything has to be checked again
+    pub async fn load(&self, storage: &Storage, pieces_count: usize) -> Option<Vec<bool>> {
+        let contents = fs::read(&self.path).await.ok()?;
+        let state = from_bytes::<ResumeState>(&contents).ok()?;
+
+        if state.info_hash[..] != self.info_hash[..]
+            || state.bitfield.len() != pieces_count.div_ceil(8)
+            || state.files != file_stamps(storage).await.ok()?
+        {
+            return None;
+        }
+
+        let mut verified = message::decode_bitfield(&state.bitfield);
+        verified.truncate(pieces_count);
+        Some(verified)
+    }
+
+    /// Records the verified pieces together with the current state of the files
+    pub async fn save(&self, storage: &Storage, verified: &[bool]) -> io::Result<()> {
+        let _lock = self.lock.lock().await;
+
+        let state = ResumeState {
+            info_hash: ByteBuf::from(self.info_hash.to_vec()),
+            bitfield: ByteBuf::from(message::encode_bitfield(verified)),
+            files: file_stamps(storage).await?,
+        };
+        let contents = to_bytes(&state).map_err(io::Error::other)?;
+
+        //? Write aside and rename, so a crash never leaves half a record behind
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        fs::write(&temporary, contents).await?;
+        fs::rename(&temporary, &self.path).await
+    }
+}
+
+async fn file_stamps(storage: &Storage) -> io::Result<Vec<FileStamp>> {
+    let mut stamps = Vec::new();
+    for path in storage.paths() {
+        let metadata = fs::metadata(path).await?;
+        let mtime = metadata
+            .modified()?
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?;
+
+        stamps.push(FileStamp {
+            length: metadata.len(),
+            mtime: u64::try_from(mtime.as_nanos()).map_err(io::Error::other)?,
+        });
+    }
+    Ok(stamps)
+}
@@ -0,0 +1,180 @@
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt};
+use tokio::sync::RwLock;
+
+use crate::info::{Info, Keys};
+
+/// Maps the contiguous byte range of a torrent onto the file(s) it is made of
+pub struct Storage {
+    files: Vec<StorageFile>,
+}
+
+struct StorageFile {
+    path: PathBuf,
+    offset: u64,
+    length: u64,
+    /// `None` for a file that does not exist when opened read-only
+    file: Option<RwLock<File>>,
+}
+
+impl Storage {
+    /// Opens (creating if needed) every file of the torrent.
+    /// A single-file torrent is written to `output_path` itself,
+    /// a multi-file torrent treats `output_path` as the directory its files go under.
+    pub async fn open(info: &Info, output_path: &Path) -> io::Result<Self> {
+        Self::open_files(info, output_path, true).await
+    }
+
+    /// Opens the torrent's files for reading only, without creating anything;
+    /// files that do not exist read as missing
+    pub async fn open_read_only(info: &Info, output_path: &Path) -> io::Result<Self> {
+        Self::open_files(info, output_path, false).await
+    }
+
+    async fn open_files(info: &Info, output_path: &Path, writable: bool) -> io::Result<Self> {
+        let mut files = Vec::new();
+        let mut offset: u64 = 0;
+
+        for (path, length) in info.files() {
+            let path = match info.keys {
+                Keys::SingleFile { .. } => output_path.to_path_buf(),
+                Keys::MultiFile { .. } => output_path.join(sanitize(&path)?),
+            };
+            if writable {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent).await?;
+                }
+            }
+
+            let file = OpenOptions::new()
+                .read(true)
+                .write(writable)
+                .create(writable)
+                .truncate(false)
+                .open(&path)
+                .await;
+            let file = match file {
+                Ok(file) => Some(RwLock::new(file)),
+                Err(e) if !writable && e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+
+            files.push(StorageFile {
+                path,
+                offset,
+                length,
+                file,
+            });
+            offset = offset.checked_add(length).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Torrent length overflows u64")
+            })?;
+        }
+
+        Ok(Self { files })
+    }
+
+    /// Paths of the torrent's files, in torrent order
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files
+            .iter()
+            .map(|storage_file| storage_file.path.as_path())
+    }
+
+    /// Reads `length` bytes starting at `offset` of the torrent, joining them across file
+    /// boundaries; fails with `UnexpectedEof` where a file is still shorter than in the torrent
+    /// and with `NotFound` where it does not exist
+    pub async fn read_at(&self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let end = offset.checked_add(length as u64).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Read past the end of the torrent",
+            )
+        })?;
+
+        let mut data = vec![0; length];
+        for storage_file in self.files.iter() {
+            let file_end = storage_file.offset + storage_file.length;
+            if file_end <= offset || storage_file.offset >= end {
+                continue;
+            }
+
+            let start = offset.max(storage_file.offset);
+            let stop = end.min(file_end);
+            let chunk = &mut data[(start - offset) as usize..(stop - offset) as usize];
+
+            let mut file = storage_file.file()?.write().await;
+            file.seek(io::SeekFrom::Start(start - storage_file.offset))
+                .await?;
+            file.read_exact(chunk).await?;
+        }
+
+        Ok(data)
+    }
+
+    /// Writes `data` starting at `offset` of the torrent, splitting it across file boundaries
+    pub async fn write_at(&self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let end = offset.checked_add(data.len() as u64).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Write past the end of the torrent",
+            )
+        })?;
+
+        for storage_file in self.files.iter() {
+            let file_end = storage_file.offset + storage_file.length;
+            if file_end <= offset || storage_file.offset >= end {
+                continue;
+            }
+
+            let start = offset.max(storage_file.offset);
+            let stop = end.min(file_end);
+            let chunk = &data[(start - offset) as usize..(stop - offset) as usize];
+
+            let mut file = storage_file.file()?.write().await;
+            file.seek(io::SeekFrom::Start(start - storage_file.offset))
+                .await?;
+            file.write_all(chunk).await?;
+            //? tokio finishes writes in the background, make sure the piece is really written
+            file.flush().await?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageFile {
+    fn file(&self) -> io::Result<&RwLock<File>> {
+        self.file.as_ref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", self.path.display()),
+            )
+        })
+    }
+}
+
+//? Torrent paths come from the network, never let them escape the output directory
+fn sanitize(path: &Path) -> io::Result<PathBuf> {
+    let mut sanitized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => sanitized.push(part),
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("Invalid file path in torrent: {}", path.display()),
+                ))
+            }
+        }
+    }
+
+    if sanitized.as_os_str().is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Empty file path in torrent",
+        ));
+    }
+    Ok(sanitized)
+}
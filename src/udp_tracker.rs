@@ -0,0 +1,462 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::{timeout_at, Instant};
+
+use crate::peers::{self, TrackerError};
+use crate::random;
+
+const PROTOCOL_ID: u64 = 0x41727101980;
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+//? BEP 15: wait 15 * 2 ^ n seconds before retransmitting, a connection ID is good for a minute
+const BASE_TIMEOUT: Duration = Duration::from_secs(15);
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+//? The spec allows up to 8 retransmits, but a client stuck that long looks hung
+const MAX_RETRANSMITS: u32 = 2;
+//? While other trackers are left to ask, a silent one gets a single short try per exchange
+const FAILOVER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connection IDs per tracker address, shared by every announce and scrape of the process
+static CONNECTION_IDS: OnceLock<Mutex<HashMap<SocketAddr, (u64, Instant)>>> = OnceLock::new();
+
+/// How long to keep asking a tracker that does not answer
+#[derive(Debug, Clone, Copy)]
+pub struct Backoff {
+    base_timeout: Duration,
+    retransmits: u32,
+}
+
+impl Backoff {
+    /// The BEP 15 back-off, for the last tracker there is to ask
+    pub const PATIENT: Backoff = Backoff {
+        base_timeout: BASE_TIMEOUT,
+        retransmits: MAX_RETRANSMITS,
+    };
+    /// One try and no retransmits, so failing over to the next tracker does not take minutes
+    pub const HASTY: Backoff = Backoff {
+        base_timeout: FAILOVER_TIMEOUT,
+        retransmits: 0,
+    };
+}
+
+pub struct AnnounceRequest {
+    pub info_hash: [u8; 20],
+    pub peer_id: [u8; 20],
+    pub downloaded: u64,
+    pub left: u64,
+    pub uploaded: u64,
+    pub port: u16,
+}
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+pub async fn announce(
+    tracker: &str,
+    request: &AnnounceRequest,
+    backoff: Backoff,
+) -> Result<Vec<String>, TrackerError> {
+    let (socket, address) = connect_socket(tracker).await?;
+    let connection_id = get_connection_id(&socket, address, backoff).await?;
+    let key = random::u32();
+
+    let response = exchange(
+        &socket,
+        address,
+        ACTION_ANNOUNCE,
+        backoff,
+        |transaction_id| {
+            let mut packet = Vec::with_capacity(98);
+            packet.extend(connection_id.to_be_bytes());
+            packet.extend(ACTION_ANNOUNCE.to_be_bytes());
+            packet.extend(transaction_id.to_be_bytes());
+            packet.extend(request.info_hash);
+            packet.extend(request.peer_id);
+            packet.extend(request.downloaded.to_be_bytes());
+            packet.extend(request.left.to_be_bytes());
+            packet.extend(request.uploaded.to_be_bytes());
+            packet.extend(0u32.to_be_bytes()); //? event: none
+            packet.extend(0u32.to_be_bytes()); //? IP address: the one the packet came from
+            packet.extend(key.to_be_bytes());
+            packet.extend((-1i32).to_be_bytes()); //? num_want: tracker default
+            packet.extend(request.port.to_be_bytes());
+            packet
+        },
+    )
+    .await?;
+
+    if response.len() < 20 {
+        return Err(TrackerError::InvalidResponse("announce response too short"));
+    }
+
+    //? Peers come in the address family of the tracker we talked to
+    let peers = &response[20..];
+    Ok(match address {
+        SocketAddr::V4(_) => peers::parse_compact_peers_v4(peers),
+        SocketAddr::V6(_) => peers::parse_compact_peers_v6(peers),
+    })
+}
+
+#[allow(dead_code)]
+pub async fn scrape(
+    tracker: &str,
+    info_hashes: &[[u8; 20]],
+    backoff: Backoff,
+) -> Result<Vec<ScrapeStats>, TrackerError> {
+    let (socket, address) = connect_socket(tracker).await?;
+    let connection_id = get_connection_id(&socket, address, backoff).await?;
+
+    let response = exchange(&socket, address, ACTION_SCRAPE, backoff, |transaction_id| {
+        let mut packet = Vec::with_capacity(16 + 20 * info_hashes.len());
+        packet.extend(connection_id.to_be_bytes());
+        packet.extend(ACTION_SCRAPE.to_be_bytes());
+        packet.extend(transaction_id.to_be_bytes());
+        for info_hash in info_hashes {
+            packet.extend(info_hash);
+        }
+        packet
+    })
+    .await?;
+
+    let stats: Vec<ScrapeStats> = response[8..]
+        .chunks_exact(12)
+        .map(|stats| ScrapeStats {
+            seeders: read_u32(&stats[0..4]),
+            completed: read_u32(&stats[4..8]),
+            leechers: read_u32(&stats[8..12]),
+        })
+        .collect();
+
+    if stats.len() != info_hashes.len() {
+        return Err(TrackerError::InvalidResponse("scrape response too short"));
+    }
+    Ok(stats)
+}
+
+async fn connect_socket(tracker: &str) -> Result<(UdpSocket, SocketAddr), TrackerError> {
+    let invalid_url = || TrackerError::InvalidUrl(tracker.to_string());
+
+    //? udp://host:port/announce, the path means nothing to the protocol
+    let authority = tracker
+        .strip_prefix("udp://")
+        .ok_or_else(invalid_url)?
+        .split('/')
+        .next()
+        .ok_or_else(invalid_url)?;
+    let address = lookup_host(authority)
+        .await?
+        .next()
+        .ok_or_else(invalid_url)?;
+
+    let socket = match address {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await?,
+        SocketAddr::V6(_) => UdpSocket::bind("[::]:0").await?,
+    };
+    socket.connect(address).await?;
+
+    Ok((socket, address))
+}
+
+async fn get_connection_id(
+    socket: &UdpSocket,
+    address: SocketAddr,
+    backoff: Backoff,
+) -> Result<u64, TrackerError> {
+    let connection_ids = CONNECTION_IDS.get_or_init(Default::default);
+
+    if let Some((connection_id, obtained)) = connection_ids.lock().unwrap().get(&address) {
+        if obtained.elapsed() < CONNECTION_ID_LIFETIME {
+            return Ok(*connection_id);
+        }
+    }
+
+    let response = exchange(socket, address, ACTION_CONNECT, backoff, |transaction_id| {
+        let mut packet = Vec::with_capacity(16);
+        packet.extend(PROTOCOL_ID.to_be_bytes());
+        packet.extend(ACTION_CONNECT.to_be_bytes());
+        packet.extend(transaction_id.to_be_bytes());
+        packet
+    })
+    .await?;
+
+    if response.len() < 16 {
+        return Err(TrackerError::InvalidResponse("connect response too short"));
+    }
+    let connection_id = u64::from_be_bytes(response[8..16].try_into().unwrap());
+
+    connection_ids
+        .lock()
+        .unwrap()
+        .insert(address, (connection_id, Instant::now()));
+
+    Ok(connection_id)
+}
+
+/// Sends the packet built for a fresh transaction ID and waits for the matching response,
+/// retransmitting with a doubling timeout as long as `backoff` allows
+async fn exchange(
+    socket: &UdpSocket,
+    address: SocketAddr,
+    action: u32,
+    backoff: Backoff,
+    build_packet: impl Fn(u32) -> Vec<u8>,
+) -> Result<Vec<u8>, TrackerError> {
+    let mut buffer = vec![0; 65_536];
+
+    for retransmit in 0..=backoff.retransmits {
+        let transaction_id = random::u32();
+        socket.send(&build_packet(transaction_id)).await?;
+
+        let deadline = Instant::now() + backoff.base_timeout * 2u32.pow(retransmit);
+        while let Ok(received) = timeout_at(deadline, socket.recv(&mut buffer)).await {
+            let response = &buffer[..received?];
+
+            //? Ignore anything that is not an answer to this transaction
+            if response.len() < 8 || read_u32(&response[4..8]) != transaction_id {
+                continue;
+            }
+
+            return match read_u32(&response[0..4]) {
+                ACTION_ERROR => {
+                    //? The tracker may have forgotten our connection ID, get a new one next time
+                    CONNECTION_IDS
+                        .get_or_init(Default::default)
+                        .lock()
+                        .unwrap()
+                        .remove(&address);
+                    Err(TrackerError::Failure(
+                        String::from_utf8_lossy(&response[8..]).to_string(),
+                    ))
+                }
+                received_action if received_action == action => Ok(response.to_vec()),
+                _ => Err(TrackerError::InvalidResponse("unexpected action")),
+            };
+        }
+    }
+
+    Err(TrackerError::Timeout)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Short enough that the retransmit tests finish right away
+    const TEST_BACKOFF: Backoff = Backoff {
+        base_timeout: Duration::from_millis(50),
+        retransmits: MAX_RETRANSMITS,
+    };
+
+    const REQUEST: AnnounceRequest = AnnounceRequest {
+        info_hash: [1; 20],
+        peer_id: [2; 20],
+        downloaded: 0,
+        left: 1_000,
+        uploaded: 0,
+        port: 6881,
+    };
+
+    /// A tracker on a port of its own, so the tests do not share connection IDs
+    async fn tracker() -> (UdpSocket, String) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        (socket, url)
+    }
+
+    /// Waits for the client's next packet, returning its action and transaction ID
+    async fn receive(tracker: &UdpSocket) -> (Vec<u8>, SocketAddr, u32, u32) {
+        let mut buffer = vec![0; 65_536];
+        let (received, client) = tracker.recv_from(&mut buffer).await.unwrap();
+        buffer.truncate(received);
+        let action = read_u32(&buffer[8..12]);
+        let transaction_id = read_u32(&buffer[12..16]);
+        (buffer, client, action, transaction_id)
+    }
+
+    async fn answer_connect(tracker: &UdpSocket, connection_id: u64) {
+        let (packet, client, action, transaction_id) = receive(tracker).await;
+        assert_eq!(
+            u64::from_be_bytes(packet[..8].try_into().unwrap()),
+            PROTOCOL_ID
+        );
+        assert_eq!(action, ACTION_CONNECT);
+
+        let mut response = Vec::new();
+        response.extend(ACTION_CONNECT.to_be_bytes());
+        response.extend(transaction_id.to_be_bytes());
+        response.extend(connection_id.to_be_bytes());
+        tracker.send_to(&response, client).await.unwrap();
+    }
+
+    /// Answers an announce with one peer, returning the connection ID it came with
+    async fn answer_announce(tracker: &UdpSocket) -> u64 {
+        let (packet, client, action, transaction_id) = receive(tracker).await;
+        assert_eq!(action, ACTION_ANNOUNCE);
+        assert_eq!(packet.len(), 98);
+        assert_eq!(packet[16..36], [1; 20]);
+        assert_eq!(packet[36..56], [2; 20]);
+        assert_eq!(u16::from_be_bytes([packet[96], packet[97]]), 6881);
+
+        let mut response = Vec::new();
+        response.extend(ACTION_ANNOUNCE.to_be_bytes());
+        response.extend(transaction_id.to_be_bytes());
+        response.extend(1_800u32.to_be_bytes()); //? interval
+        response.extend(0u32.to_be_bytes()); //? leechers
+        response.extend(1u32.to_be_bytes()); //? seeders
+        response.extend([10, 0, 0, 1, 0x1A, 0xE1]);
+        tracker.send_to(&response, client).await.unwrap();
+
+        u64::from_be_bytes(packet[..8].try_into().unwrap())
+    }
+
+    #[tokio::test]
+    async fn announce_connects_first() {
+        let (tracker, url) = tracker().await;
+        let (peers, connection_id) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            answer_connect(&tracker, 42).await;
+            answer_announce(&tracker).await
+        });
+
+        assert_eq!(peers.unwrap(), ["10.0.0.1:6881"]);
+        assert_eq!(connection_id, 42);
+    }
+
+    #[tokio::test]
+    async fn connection_id_is_reused() {
+        let (tracker, url) = tracker().await;
+        let (first, _) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            answer_connect(&tracker, 42).await;
+            answer_announce(&tracker).await
+        });
+        first.unwrap();
+
+        //? Still within the lifetime, so the tracker only sees the announce
+        let (second, connection_id) = tokio::join!(
+            announce(&url, &REQUEST, TEST_BACKOFF),
+            answer_announce(&tracker)
+        );
+        second.unwrap();
+        assert_eq!(connection_id, 42);
+    }
+
+    #[tokio::test]
+    async fn error_fails_the_announce_and_forgets_the_connection_id() {
+        let (tracker, url) = tracker().await;
+        let (result, _) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            answer_connect(&tracker, 42).await;
+            let (_, client, _, transaction_id) = receive(&tracker).await;
+            let mut response = Vec::new();
+            response.extend(ACTION_ERROR.to_be_bytes());
+            response.extend(transaction_id.to_be_bytes());
+            response.extend(b"unregistered torrent");
+            tracker.send_to(&response, client).await.unwrap();
+        });
+        assert!(matches!(
+            result,
+            Err(TrackerError::Failure(message)) if message == "unregistered torrent"
+        ));
+
+        //? The next announce has to connect again
+        let (result, connection_id) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            answer_connect(&tracker, 43).await;
+            answer_announce(&tracker).await
+        });
+        result.unwrap();
+        assert_eq!(connection_id, 43);
+    }
+
+    #[tokio::test]
+    async fn answers_to_other_transactions_are_ignored() {
+        let (tracker, url) = tracker().await;
+        let (result, connection_id) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            let (_, client, _, transaction_id) = receive(&tracker).await;
+            for (transaction_id, connection_id) in [
+                (transaction_id.wrapping_add(1), 41u64),
+                (transaction_id, 42),
+            ] {
+                let mut response = Vec::new();
+                response.extend(ACTION_CONNECT.to_be_bytes());
+                response.extend(transaction_id.to_be_bytes());
+                response.extend(connection_id.to_be_bytes());
+                tracker.send_to(&response, client).await.unwrap();
+            }
+            answer_announce(&tracker).await
+        });
+        result.unwrap();
+        assert_eq!(connection_id, 42);
+    }
+
+    #[tokio::test]
+    async fn dropped_packets_are_retransmitted() {
+        let (tracker, url) = tracker().await;
+        let (result, connection_id) = tokio::join!(announce(&url, &REQUEST, TEST_BACKOFF), async {
+            let (_, _, action, _) = receive(&tracker).await;
+            assert_eq!(action, ACTION_CONNECT);
+            answer_connect(&tracker, 42).await;
+            answer_announce(&tracker).await
+        });
+        result.unwrap();
+        assert_eq!(connection_id, 42);
+    }
+
+    #[tokio::test]
+    async fn silent_tracker_times_out() {
+        let (tracker, url) = tracker().await;
+        let result = announce(&url, &REQUEST, TEST_BACKOFF).await;
+        assert!(matches!(result, Err(TrackerError::Timeout)));
+
+        //? The first try and every retransmit
+        for _ in 0..=MAX_RETRANSMITS {
+            let (_, _, action, _) = receive(&tracker).await;
+            assert_eq!(action, ACTION_CONNECT);
+        }
+    }
+
+    #[tokio::test]
+    async fn scrape_returns_stats_per_info_hash() {
+        let (tracker, url) = tracker().await;
+        let info_hashes = [[1; 20], [3; 20]];
+        let (stats, _) = tokio::join!(scrape(&url, &info_hashes, TEST_BACKOFF), async {
+            answer_connect(&tracker, 42).await;
+            let (packet, client, action, transaction_id) = receive(&tracker).await;
+            assert_eq!(action, ACTION_SCRAPE);
+            assert_eq!(packet[16..], [[1; 20], [3; 20]].concat());
+
+            let mut response = Vec::new();
+            response.extend(ACTION_SCRAPE.to_be_bytes());
+            response.extend(transaction_id.to_be_bytes());
+            for (seeders, completed, leechers) in [(5u32, 10u32, 2u32), (0, 1, 7)] {
+                response.extend(seeders.to_be_bytes());
+                response.extend(completed.to_be_bytes());
+                response.extend(leechers.to_be_bytes());
+            }
+            tracker.send_to(&response, client).await.unwrap();
+        });
+
+        let stats = stats.unwrap();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(
+            (stats[0].seeders, stats[0].completed, stats[0].leechers),
+            (5, 10, 2)
+        );
+        assert_eq!(
+            (stats[1].seeders, stats[1].completed, stats[1].leechers),
+            (0, 1, 7)
+        );
+    }
+}
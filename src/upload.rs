@@ -0,0 +1,163 @@
+use std::io;
+use std::sync::Arc;
+use tokio::sync::RwLock;
+
+use crate::download_piece;
+use crate::handshake::PeerConnection;
+use crate::info::Metadata;
+use crate::message::{self, PeerMessage};
+use crate::picker::WorkQueue;
+use crate::rate_limit::RateLimit;
+use crate::storage::Storage;
+
+/// Largest block we serve, peers ask for 16 KiB but some go up to this
+const MAX_REQUEST_LENGTH: u32 = 128 * 1_024;
+/// Requests a peer may have waiting at once, the `reqq` most clients assume; more are dropped
+const MAX_QUEUED_REQUESTS: usize = 250;
+
+/// Serves verified pieces out of the storage to the peers that ask for them
+pub struct Uploader {
+    metadata: Arc<RwLock<Metadata>>,
+    storage: Arc<Storage>,
+    queue: Arc<WorkQueue>,
+    limit: RateLimit,
+}
+
+impl Uploader {
+    pub fn new(
+        metadata: Arc<RwLock<Metadata>>,
+        storage: Arc<Storage>,
+        queue: Arc<WorkQueue>,
+        limit: RateLimit,
+    ) -> Self {
+        Self {
+            metadata,
+            storage,
+            queue,
+            limit,
+        }
+    }
+
+    /// Sends our bitfield, the first thing a peer should hear from us after the handshake
+    pub async fn greet(&self, connection: &PeerConnection) -> Result<(), io::Error> {
+        let verified = self.queue.verified();
+        connection.state.lock().unwrap().announced = verified.clone();
+
+        //? Having nothing, the bitfield may be left out
+        if verified.contains(&true) {
+            let bitfield = PeerMessage::Bitfield(message::encode_bitfield(&verified));
+            download_piece::send_message(connection, &bitfield).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a `have` for every piece we verified since the last time
+    pub async fn announce(&self, connection: &PeerConnection) -> Result<(), io::Error> {
+        let verified = self.queue.verified();
+        let haves: Vec<usize> = {
+            let mut state = connection.state.lock().unwrap();
+            state.announced.resize(verified.len(), false);
+            let haves = (0..verified.len())
+                .filter(|&index| verified[index] && !state.announced[index])
+                .collect();
+            state.announced = verified;
+            haves
+        };
+
+        for piece_index in haves {
+            let have = PeerMessage::Have {
+                piece_index: piece_index as u32,
+            };
+            download_piece::send_message(connection, &have).await?;
+        }
+        Ok(())
+    }
+
+    /// Queues up the peer's requests while the choker lets it download from us, for `upload`
+    /// to answer. Returns the message if whoever is reading still has to look at it
+    pub fn serve(&self, connection: &PeerConnection, message: PeerMessage) -> Option<PeerMessage> {
+        match message {
+            PeerMessage::Request {
+                piece_index,
+                begin,
+                length,
+            } => {
+                let mut state = connection.state.lock().unwrap();
+                if !state.am_choking && state.requested.len() < MAX_QUEUED_REQUESTS {
+                    state.requested.push_back((piece_index, begin, length));
+                    connection.requested.notify_one();
+                }
+                None
+            }
+            PeerMessage::Cancel {
+                piece_index,
+                begin,
+                length,
+            } => {
+                let mut state = connection.state.lock().unwrap();
+                state
+                    .requested
+                    .retain(|&request| request != (piece_index, begin, length));
+                None
+            }
+            message => Some(message),
+        }
+    }
+
+    /// Sends the blocks the peer asked for, in order and within the upload limit. Runs next to
+    /// reading the peer's messages, so a throttled upload never holds up what we download
+    pub async fn upload(&self, connection: &PeerConnection) -> Result<(), io::Error> {
+        loop {
+            let request = connection.state.lock().unwrap().requested.pop_front();
+            match request {
+                Some((piece_index, begin, length)) => {
+                    self.send_block(connection, piece_index, begin, length)
+                        .await?
+                }
+                //? A request queued in between left a permit, so this returns right away
+                None => connection.requested.notified().await,
+            }
+        }
+    }
+
+    async fn send_block(
+        &self,
+        connection: &PeerConnection,
+        piece_index: u32,
+        begin: u32,
+        length: u32,
+    ) -> Result<(), io::Error> {
+        //? Only hand out verified data, and never more than the piece holds
+        if length == 0
+            || length > MAX_REQUEST_LENGTH
+            || !self.queue.is_verified(piece_index as usize)
+        {
+            return Ok(());
+        }
+        let metadata = self.metadata.read().await;
+        let (Some(piece_length), Some(piece_position)) = (
+            metadata.info.piece_size(piece_index),
+            metadata.info.piece_offset(piece_index),
+        ) else {
+            return Ok(());
+        };
+        drop(metadata);
+        if begin as u64 + length as u64 > piece_length {
+            return Ok(());
+        }
+
+        self.limit.acquire(length as usize).await;
+        let block = self
+            .storage
+            .read_at(piece_position + begin as u64, length as usize)
+            .await?;
+        let piece = PeerMessage::Piece {
+            piece_index,
+            begin,
+            block,
+        };
+        download_piece::send_message(connection, &piece).await?;
+        connection.state.lock().unwrap().uploaded += length as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::io;
+use std::path::Path;
+use tokio::sync::oneshot;
+
+use crate::download_piece;
+use crate::info::{Info, Metadata};
+use crate::storage::Storage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PieceStatus {
+    Valid,
+    /// All of the piece is there, but it does not match its hash
+    Corrupt,
+    /// Some of the piece lies past the end of a file, or in a file that does not exist
+    Missing,
+}
+
+/// Hash-checks every piece of the data on disk, reading the next pieces while earlier
+/// ones are hashed on the rayon thread pool
+pub async fn check_pieces(info: &Info, storage: &Storage) -> Result<Vec<PieceStatus>, io::Error> {
+    let piece_hashes = info.get_piece_hashes();
+    //? Enough to keep every thread busy without reading the whole torrent into memory
+    let max_hashing = rayon::current_num_threads() * 2;
+
+    let mut statuses = vec![PieceStatus::Missing; piece_hashes.len()];
+    let mut hashing = VecDeque::new();
+    for (piece_index, piece_hash) in piece_hashes.into_iter().enumerate() {
+        let (Some(piece_length), Some(piece_position)) = (
+            info.piece_size(piece_index as u32),
+            info.piece_offset(piece_index as u32),
+        ) else {
+            continue;
+        };
+        let piece_length = usize::try_from(piece_length).map_err(io::Error::other)?;
+
+        match storage.read_at(piece_position, piece_length).await {
+            Ok(piece) => {
+                hashing.push_back((piece_index, download_piece::spawn_hash(piece, piece_hash)))
+            }
+            Err(e)
+                if e.kind() == io::ErrorKind::UnexpectedEof
+                    || e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        while hashing.len() >= max_hashing {
+            let (piece_index, result) = hashing.pop_front().unwrap();
+            statuses[piece_index] = hashed_status(result).await;
+        }
+    }
+    for (piece_index, result) in hashing {
+        statuses[piece_index] = hashed_status(result).await;
+    }
+    Ok(statuses)
+}
+
+async fn hashed_status(result: oneshot::Receiver<(Vec<u8>, bool)>) -> PieceStatus {
+    match result.await.expect("Hashing thread panicked") {
+        (_, true) => PieceStatus::Valid,
+        (_, false) => PieceStatus::Corrupt,
+    }
+}
+
+/// Checks the data at `path` against the torrent and prints a report,
+/// returns whether every piece is valid
+pub async fn verify(metadata: &Metadata, path: &Path) -> Result<bool, io::Error> {
+    let storage = Storage::open_read_only(&metadata.info, path).await?;
+    let statuses = check_pieces(&metadata.info, &storage).await?;
+
+    for (label, status) in [
+        ("Valid", PieceStatus::Valid),
+        ("Corrupt", PieceStatus::Corrupt),
+        ("Missing", PieceStatus::Missing),
+    ] {
+        let pieces: Vec<usize> = (0..statuses.len())
+            .filter(|&index| statuses[index] == status)
+            .collect();
+        if pieces.is_empty() {
+            println!("{}: 0", label);
+        } else {
+            println!("{}: {} ({})", label, pieces.len(), format_ranges(&pieces));
+        }
+    }
+
+    let valid = statuses
+        .iter()
+        .filter(|&&status| status == PieceStatus::Valid)
+        .count();
+    let percentage = if statuses.is_empty() {
+        100.0
+    } else {
+        valid as f64 * 100.0 / statuses.len() as f64
+    };
+    println!("{:.2}% of {} pieces valid", percentage, statuses.len());
+
+    Ok(valid == statuses.len())
+}
+
+//? Sorted piece indices as "0-3, 7, 9-10", a list of every index would be unreadable
+fn format_ranges(pieces: &[usize]) -> String {
+    let mut ranges = String::new();
+    let mut index = 0;
+    while index < pieces.len() {
+        let start = pieces[index];
+        let mut end = start;
+        while index + 1 < pieces.len() && pieces[index + 1] == end + 1 {
+            index += 1;
+            end = pieces[index];
+        }
+        if !ranges.is_empty() {
+            ranges.push_str(", ");
+        }
+        if start == end {
+            write!(ranges, "{}", start).unwrap();
+        } else {
+            write!(ranges, "{}-{}", start, end).unwrap();
+        }
+        index += 1;
+    }
+    ranges
+}